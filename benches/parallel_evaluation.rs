@@ -0,0 +1,197 @@
+// compares the sequential and parallel code paths on a synthetic registry of 100k users and 50k assets
+// run with cargo bench --bench parallel_evaluation
+use std::{
+    collections::HashMap,
+    hint::black_box,
+    time::{Duration, Instant},
+};
+
+use rhubarb::{
+    access_matrix::AccessMatrix,
+    database::{Database, SetRegistry, StatementDependencies},
+    parse_command::Submission,
+    parse_set,
+    permission_hierarchy::PermissionHierarchy,
+    set_cache::SetCache,
+};
+
+const USERS: u32 = 100_000;
+const ASSETS: u32 = 50_000;
+const STATEMENTS: usize = 400;
+const ITERATIONS: u32 = 20;
+
+const DESIGNATIONS: [&str; 7] = [
+    "intern",
+    "associate",
+    "senior",
+    "manager",
+    "director",
+    "partner",
+    "contractor",
+];
+
+fn user_registry() -> SetRegistry {
+    let mut registry = SetRegistry::new();
+    for i in 0..USERS {
+        let id = format!("user{}@example.com", i);
+        registry.insert_id(&id);
+        registry.insert(format!("department:d{}", i % 50), id.clone());
+        registry.insert(format!("division:v{}", i % 10), id.clone());
+        registry.insert(
+            format!("designation:{}", DESIGNATIONS[i as usize % 7]),
+            id.clone(),
+        );
+        if i % 3 == 0 {
+            registry.insert("security_clearance:true".to_string(), id);
+        }
+    }
+    registry
+}
+
+fn asset_registry() -> SetRegistry {
+    let mut registry = SetRegistry::new();
+    for i in 0..ASSETS {
+        let id = format!("s{}.t{}", i % 100, i);
+        registry.insert_id(&id);
+        registry.insert(format!("schema:s{}", i % 100), id.clone());
+        registry.insert(format!("table:t{}", i % 1000), id);
+    }
+    registry
+}
+
+fn database() -> Database {
+    Database {
+        asset_registry: asset_registry(),
+        user_registry: user_registry(),
+        asset_registry_history: Vec::new(),
+        user_registry_history: Vec::new(),
+        valid_permissions: vec!["READ".to_string(), "WRITE".to_string()],
+        permission_hierarchy: PermissionHierarchy::default(),
+        statement_log: HashMap::new(),
+        access_matrix: AccessMatrix::new(),
+        dependencies: StatementDependencies::default(),
+        log_sequence: 0,
+    }
+}
+
+// statements share the designation fragment, as real policies tend to
+fn policy() -> Vec<String> {
+    (0..STATEMENTS)
+        .map(|k| {
+            format!(
+                "GRANT READ ON (schema:s{} OR schema:s{}) EXCEPT table:t{} \
+                 TO (department:d{} OR department:d{} OR division:v{}) \
+                 AND (designation:senior OR designation:partner) EXCEPT security_clearance:true",
+                k % 100,
+                (k + 37) % 100,
+                k % 1000,
+                k % 50,
+                (k + 7) % 50,
+                k % 10,
+            )
+        })
+        .collect()
+}
+
+fn wide_expression() -> String {
+    let departments: Vec<String> = (0..25).map(|d| format!("department:d{}", d)).collect();
+    format!(
+        "(({}) AND (designation:senior OR designation:partner OR designation:director)) \
+         EXCEPT (division:v3 OR NOT security_clearance:true)",
+        departments.join(" OR ")
+    )
+}
+
+fn time<T>(mut f: impl FnMut() -> T) -> Duration {
+    // warm up
+    black_box(f());
+
+    let start = Instant::now();
+    for _ in 0..ITERATIONS {
+        black_box(f());
+    }
+    start.elapsed() / ITERATIONS
+}
+
+// the sequential code path against the parallel one, on the global rayon pool
+fn compare<S, P>(label: &str, sequential: impl FnMut() -> S, parallel: impl FnMut() -> P) {
+    let sequential = time(sequential);
+    let parallel = time(parallel);
+
+    println!(
+        "{:<20} sequential {:>10.2?}  parallel {:>10.2?}  speed-up {:.1}x",
+        label,
+        sequential,
+        parallel,
+        sequential.as_secs_f64() / parallel.as_secs_f64()
+    );
+}
+
+fn main() {
+    println!(
+        "{} users, {} assets, {} statements, {} threads",
+        USERS,
+        ASSETS,
+        STATEMENTS,
+        rayon::current_num_threads()
+    );
+    let mut database = database();
+    let submission = Submission::new("bench");
+
+    // a single wide expression, evaluate against evaluate_parallel
+    let expr = parse_set::parse_set_expr(&wide_expression()).unwrap();
+    let registry = &database.user_registry;
+    compare(
+        "single expression",
+        || parse_set::evaluate(&expr, registry).len(),
+        || parse_set::evaluate_parallel(&expr, registry).len(),
+    );
+
+    // resolving a whole policy file, one command after another against resolve_commands
+    let policy = policy();
+    let inputs: Vec<&str> = policy.iter().map(String::as_str).collect();
+    compare(
+        "policy file",
+        || {
+            inputs
+                .iter()
+                .map(|input| database.resolve_command_as(input, &submission))
+                .collect::<Result<Vec<_>, _>>()
+                .unwrap()
+                .len()
+        },
+        || {
+            database
+                .resolve_commands(&inputs, &submission)
+                .unwrap()
+                .len()
+        },
+    );
+
+    // re-resolving the statement log, as after a registry refresh
+    // one statement after another through shared caches against resolve_statement_sets
+    for input in &inputs {
+        let statement = database.prepare_statement(input, &submission).unwrap();
+        database
+            .statement_log
+            .insert(statement.statement_id.clone(), statement);
+    }
+    let mut sequential_log = database.statement_log.clone();
+    let (asset_registry, user_registry) = (&database.asset_registry, &database.user_registry);
+    let mut parallel_database = database.clone();
+    compare(
+        "statement log",
+        || {
+            let (mut asset_cache, mut user_cache) = (SetCache::new(), SetCache::new());
+            for statement in sequential_log.values_mut() {
+                statement.resolve_against(
+                    asset_registry,
+                    &mut asset_cache,
+                    user_registry,
+                    &mut user_cache,
+                );
+            }
+        },
+        || parallel_database.resolve_statement_sets(),
+    );
+}
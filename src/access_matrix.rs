@@ -0,0 +1,151 @@
+use std::collections::{HashMap, HashSet};
+
+use chrono::{DateTime, Utc};
+
+use crate::database::{AtomicPermission, Validity};
+
+// a slice of the matrix, every cell is evaluated independently
+// so a slice can be recomputed on its own when only its user or asset changed
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MatrixScope<'a> {
+    All,
+    User(&'a str),
+    Asset(&'a str),
+    // several rows or columns at once e.g. every id a registry refresh changed
+    Users(&'a HashSet<String>),
+    Assets(&'a HashSet<String>),
+}
+
+// materialized view of the effective permissions
+// user id maps to asset id maps to permission maps to the windows in which it is held
+#[derive(Debug, Clone, Default)]
+pub struct AccessMatrix {
+    data: HashMap<String, HashMap<String, HashMap<String, Vec<Validity>>>>,
+}
+
+impl AccessMatrix {
+    pub fn new() -> AccessMatrix {
+        AccessMatrix {
+            data: HashMap::new(),
+        }
+    }
+
+    // windows are expected in time order, a window starting where the last one ends extends it
+    pub fn insert(&mut self, atomic: AtomicPermission, validity: Validity) {
+        let windows = self
+            .data
+            .entry(atomic.user_id)
+            .or_default()
+            .entry(atomic.asset_id)
+            .or_default()
+            .entry(atomic.permission)
+            .or_default();
+
+        match windows.last_mut() {
+            Some(last) if last.valid_until.is_some() && last.valid_until == validity.valid_from => {
+                last.valid_until = validity.valid_until;
+            }
+            _ => windows.push(validity),
+        }
+    }
+
+    pub fn check(
+        &self,
+        user_id: &str,
+        asset_id: &str,
+        permission: &str,
+        at: DateTime<Utc>,
+    ) -> bool {
+        self.data
+            .get(user_id)
+            .and_then(|assets| assets.get(asset_id))
+            .and_then(|permissions| permissions.get(permission))
+            .is_some_and(|windows| windows.iter().any(|window| window.contains(at)))
+    }
+
+    pub fn permissions(&self, user_id: &str, asset_id: &str, at: DateTime<Utc>) -> HashSet<String> {
+        self.data
+            .get(user_id)
+            .and_then(|assets| assets.get(asset_id))
+            .map(|permissions| {
+                permissions
+                    .iter()
+                    .filter(|(_, windows)| windows.iter().any(|window| window.contains(at)))
+                    .map(|(permission, _)| permission.clone())
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    // every atomic permission held at the given time, sorted
+    pub fn export(&self, at: DateTime<Utc>) -> Vec<AtomicPermission> {
+        let mut atomics: Vec<AtomicPermission> = self
+            .data
+            .iter()
+            .flat_map(|(user_id, assets)| {
+                assets.iter().flat_map(move |(asset_id, permissions)| {
+                    permissions
+                        .iter()
+                        .filter(move |(_, windows)| {
+                            windows.iter().any(|window| window.contains(at))
+                        })
+                        .map(move |(permission, _)| AtomicPermission {
+                            user_id: user_id.clone(),
+                            asset_id: asset_id.clone(),
+                            permission: permission.clone(),
+                        })
+                })
+            })
+            .collect();
+        atomics.sort();
+        atomics
+    }
+
+    pub fn remove(&mut self, scope: MatrixScope) {
+        match scope {
+            MatrixScope::All => self.data.clear(),
+            MatrixScope::User(user_id) => {
+                self.data.remove(user_id);
+            }
+            MatrixScope::Asset(asset_id) => {
+                self.data.retain(|_, assets| {
+                    assets.remove(asset_id);
+                    !assets.is_empty()
+                });
+            }
+            MatrixScope::Users(user_ids) => {
+                self.data.retain(|user_id, _| !user_ids.contains(user_id));
+            }
+            MatrixScope::Assets(asset_ids) => {
+                self.data.retain(|_, assets| {
+                    assets.retain(|asset_id, _| !asset_ids.contains(asset_id));
+                    !assets.is_empty()
+                });
+            }
+        }
+    }
+
+    // number of atomic permissions held at any time
+    pub fn len(&self) -> usize {
+        self.data
+            .values()
+            .flat_map(|assets| assets.values())
+            .map(|permissions| permissions.len())
+            .sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+}
+
+// permissions collected this way are held at all times
+impl FromIterator<AtomicPermission> for AccessMatrix {
+    fn from_iter<I: IntoIterator<Item = AtomicPermission>>(iter: I) -> Self {
+        let mut matrix = AccessMatrix::new();
+        for atomic in iter {
+            matrix.insert(atomic, Validity::default());
+        }
+        matrix
+    }
+}
@@ -1,3 +1,5 @@
+use std::collections::HashMap;
+
 use serde::Deserialize;
 
 #[derive(Deserialize)]
@@ -8,4 +10,7 @@ pub struct Config {
 #[derive(Deserialize)]
 pub struct DatabaseConfig {
     pub valid_permissions: Vec<String>,
+    // permission maps to the permissions it directly implies e.g. ADMIN = ["WRITE"]
+    #[serde(default)]
+    pub permission_implications: HashMap<String, Vec<String>>,
 }
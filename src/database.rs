@@ -1,26 +1,54 @@
-use std::collections::{HashMap, HashSet};
+use std::{
+    collections::{HashMap, HashSet},
+    fmt,
+    sync::atomic::{AtomicU64, Ordering},
+};
 
 use chrono::{DateTime, Utc};
+use roaring::RoaringBitmap;
+use serde::{Deserialize, Serialize};
 
-// need to be able to deserialize this struct (at least the first 3 fields) into disc
+use crate::{
+    access_matrix::{AccessMatrix, MatrixScope},
+    error::Result,
+    history::RegistryChange,
+    parse_command::{
+        CommandParseResult, CommandParseResultMetadata, DatabaseOperationType, Submission,
+    },
+    parse_set::{ResolvedSet, SetExpr},
+    permission_hierarchy::PermissionHierarchy,
+    set_cache::SetCache,
+};
+
+// persisted with save_snapshot, see snapshot.rs
 #[derive(Debug, Clone)]
 pub struct Database {
     // derived from database metadata or information_schema
     pub asset_registry: SetRegistry,
     // derived from employee databases or other employee registries
     pub user_registry: SetRegistry,
+    // changes to the registries, oldest first, for point-in-time queries
+    pub asset_registry_history: Vec<RegistryChange>,
+    pub user_registry_history: Vec<RegistryChange>,
     // valid permissions are defined in config
     pub valid_permissions: Vec<String>,
+    // implications between permissions are defined in config
+    pub permission_hierarchy: PermissionHierarchy,
     // mutable map of permissions statements (which expand to granular permissions)
+    // keyed by statement id
     pub statement_log: HashMap<String, Permission>,
-    // Access matrix WIP
-    //pub access_matrix:
+    // materialized effective permissions, rebuilt whenever the statement log changes
+    pub access_matrix: AccessMatrix,
+    // set keys each statement depends on, rebuilt along with the access matrix
+    pub dependencies: StatementDependencies,
+    // sequence of the last write-ahead log record applied, 0 when none has been
+    pub log_sequence: u64,
 }
 
 impl Database {
     pub fn status_report(&self) {
-        let num_asset_ids = self.asset_registry.ids.len();
-        let num_user_ids = self.user_registry.ids.len();
+        let num_asset_ids = self.asset_registry.id_count();
+        let num_user_ids = self.user_registry.id_count();
         let valid_permissions = self.valid_permissions.clone();
         let total_permutations = num_asset_ids * num_user_ids * valid_permissions.len();
         let num_statements = self.statement_log.len();
@@ -30,46 +58,1181 @@ impl Database {
         println!("Valid permissions {:?}", valid_permissions);
         println!("Maximum permission combinations: {}", total_permutations,);
         println!("Statements in effect: {}", num_statements);
+        println!(
+            "Atomic permissions in effect: {}",
+            self.access_matrix.export(Utc::now()).len()
+        );
+    }
+
+    // access checks default to the current time, the _at variants take an evaluation time
+    pub fn check(&self, user_id: &str, asset_id: &str, permission: &str) -> bool {
+        self.check_at(user_id, asset_id, permission, Utc::now())
+    }
+
+    pub fn check_at(
+        &self,
+        user_id: &str,
+        asset_id: &str,
+        permission: &str,
+        at: DateTime<Utc>,
+    ) -> bool {
+        self.access_matrix.check(user_id, asset_id, permission, at)
+    }
+
+    pub fn permissions_for(&self, user_id: &str, asset_id: &str) -> HashSet<String> {
+        self.permissions_for_at(user_id, asset_id, Utc::now())
+    }
+
+    pub fn permissions_for_at(
+        &self,
+        user_id: &str,
+        asset_id: &str,
+        at: DateTime<Utc>,
+    ) -> HashSet<String> {
+        self.access_matrix.permissions(user_id, asset_id, at)
+    }
+
+    // every atomic permission in effect at the given time, sorted
+    pub fn export_access(&self, at: DateTime<Utc>) -> Vec<AtomicPermission> {
+        self.access_matrix.export(at)
+    }
+
+    pub fn rebuild_access_matrix(&mut self) {
+        self.dependencies = StatementDependencies::new(&self.statement_log);
+        self.recompute_access_matrix(MatrixScope::All);
+    }
+
+    // effective permissions only change where a validity window starts or ends
+    // so the log is evaluated once per interval between consecutive boundaries
+    pub fn recompute_access_matrix(&mut self, scope: MatrixScope) {
+        let mut boundaries: Vec<DateTime<Utc>> = self
+            .statement_log
+            .values()
+            .flat_map(|statement| {
+                [
+                    statement.validity.valid_from,
+                    statement.validity.valid_until,
+                ]
+            })
+            .flatten()
+            .collect();
+        boundaries.sort();
+        boundaries.dedup();
+
+        self.access_matrix.remove(scope);
+
+        for i in 0..=boundaries.len() {
+            let window = Validity {
+                valid_from: i.checked_sub(1).map(|previous| boundaries[previous]),
+                valid_until: boundaries.get(i).copied(),
+            };
+            let at = window.valid_from.unwrap_or(DateTime::<Utc>::MIN_UTC);
+
+            for atomic in self.effective_permissions_in(at, scope) {
+                self.access_matrix.insert(atomic, window);
+            }
+        }
+    }
+
+    // resolves the command and stores it in the statement log, returning the statement id
+    pub fn apply_command(&mut self, input: &str) -> Result<String> {
+        self.apply_command_as(input, &Submission::new("unknown"))
+    }
+
+    // as apply_command, recording who submitted the command and why
+    pub fn apply_command_as(&mut self, input: &str, submission: &Submission) -> Result<String> {
+        let statement = self.prepare_statement(input, submission)?;
+        Ok(self.insert_statement(statement))
+    }
+
+    // resolves the command into the next statement without storing it
+    pub fn prepare_statement(&self, input: &str, submission: &Submission) -> Result<Permission> {
+        let command = self.resolve_command_as(input, submission)?;
+        Ok(Permission::new(self.next_sequence(), command))
+    }
+
+    // stores a prepared statement and rebuilds the access matrix, returning the statement id
+    pub fn insert_statement(&mut self, statement: Permission) -> String {
+        let statement_id = statement.statement_id.clone();
+
+        self.statement_log.insert(statement_id.clone(), statement);
+        self.rebuild_access_matrix();
+
+        statement_id
+    }
+
+    fn next_sequence(&self) -> u64 {
+        self.last_sequence() + 1
+    }
+
+    // sequence of the most recently applied statement, 0 when the log is empty
+    pub fn last_sequence(&self) -> u64 {
+        self.statement_log
+            .values()
+            .map(|statement| statement.sequence)
+            .max()
+            .unwrap_or(0)
+    }
+
+    // statements sorted into the order they were applied
+    pub fn ordered_statements(&self) -> Vec<&Permission> {
+        let mut statements: Vec<&Permission> = self.statement_log.values().collect();
+        statements.sort_by_key(|statement| statement.sequence);
+        statements
+    }
+
+    pub fn effective_permissions(&self) -> HashSet<AtomicPermission> {
+        self.effective_permissions_at(Utc::now())
+    }
+
+    // effective permissions use deny-overrides-allow semantics
+    // any atomic permission covered by a DENY is removed regardless of statement order
+    pub fn effective_permissions_at(&self, at: DateTime<Utc>) -> HashSet<AtomicPermission> {
+        self.effective_permissions_in(at, MatrixScope::All)
+    }
+
+    pub fn effective_permissions_in(
+        &self,
+        at: DateTime<Utc>,
+        scope: MatrixScope,
+    ) -> HashSet<AtomicPermission> {
+        self.effective_permissions_of(&self.ordered_statements(), at, scope)
+    }
+
+    // as effective_permissions_in, over the given statements in the order they were applied
+    // e.g. the log with some statements re-resolved against a candidate registry
+    pub fn effective_permissions_of(
+        &self,
+        statements: &[&Permission],
+        at: DateTime<Utc>,
+        scope: MatrixScope,
+    ) -> HashSet<AtomicPermission> {
+        let denied = self.denied_permissions_of(statements, scope);
+
+        self.allowed_permissions_of(statements, at, scope)
+            .into_iter()
+            .filter(|atomic| !denied.contains(atomic))
+            .collect()
+    }
+
+    pub fn allowed_permissions(&self) -> HashSet<AtomicPermission> {
+        self.allowed_permissions_at(Utc::now())
+    }
+
+    // replays the statement log in order - GRANT adds atomic permissions, REVOKE withdraws them
+    // grants outside their validity window at the given time are skipped
+    // REVOKE has no window and applies at every time, even before it was received
+    // the surviving permissions are then expanded to everything they imply
+    // DENY statements are ignored here, see effective_permissions
+    pub fn allowed_permissions_at(&self, at: DateTime<Utc>) -> HashSet<AtomicPermission> {
+        self.allowed_permissions_in(at, MatrixScope::All)
+    }
+
+    pub fn allowed_permissions_in(
+        &self,
+        at: DateTime<Utc>,
+        scope: MatrixScope,
+    ) -> HashSet<AtomicPermission> {
+        self.allowed_permissions_of(&self.ordered_statements(), at, scope)
+    }
+
+    fn allowed_permissions_of(
+        &self,
+        statements: &[&Permission],
+        at: DateTime<Utc>,
+        scope: MatrixScope,
+    ) -> HashSet<AtomicPermission> {
+        let mut allowed = HashSet::new();
+
+        for statement in statements {
+            if !statement.validity.contains(at) {
+                continue;
+            }
+
+            match statement.database_operation {
+                DatabaseOperationType::Grant(_) => {
+                    allowed.extend(statement.atomic_permissions_in(scope))
+                }
+                DatabaseOperationType::Revoke(_) => {
+                    for atomic in statement.atomic_permissions_in(scope) {
+                        allowed.remove(&atomic);
+                    }
+                }
+                DatabaseOperationType::Deny(_) => {}
+            }
+        }
+
+        self.permission_hierarchy.expand_implied(allowed)
+    }
+
+    // union of every DENY statement - a REVOKE does not lift a DENY
+    // like REVOKE a DENY has no window and applies at every time
+    // denying a permission also denies every permission that implies it
+    pub fn denied_permissions(&self) -> HashSet<AtomicPermission> {
+        self.denied_permissions_in(MatrixScope::All)
+    }
+
+    pub fn denied_permissions_in(&self, scope: MatrixScope) -> HashSet<AtomicPermission> {
+        self.denied_permissions_of(&self.ordered_statements(), scope)
+    }
+
+    fn denied_permissions_of(
+        &self,
+        statements: &[&Permission],
+        scope: MatrixScope,
+    ) -> HashSet<AtomicPermission> {
+        let denied = statements
+            .iter()
+            .filter(|statement| {
+                matches!(statement.database_operation, DatabaseOperationType::Deny(_))
+            })
+            .flat_map(|statement| statement.atomic_permissions_in(scope));
+
+        self.permission_hierarchy.expand_implying(denied)
+    }
+
+    // reports which grants are masked by which deny
+    // only atomic permissions that survive REVOKE statements are reported
+    pub fn masked_grants(&self) -> Vec<MaskedGrant> {
+        let allowed = self.allowed_permissions();
+        let statements = self.ordered_statements();
+
+        let mut masked_grants = Vec::new();
+
+        for grant in &statements {
+            if !matches!(grant.database_operation, DatabaseOperationType::Grant(_)) {
+                continue;
+            }
+
+            for deny in &statements {
+                if !matches!(deny.database_operation, DatabaseOperationType::Deny(_)) {
+                    continue;
+                }
+
+                let denied = self
+                    .permission_hierarchy
+                    .expand_implying(deny.atomic_permissions());
+                let mut masked: Vec<AtomicPermission> = self
+                    .permission_hierarchy
+                    .expand_implied(grant.atomic_permissions())
+                    .into_iter()
+                    .filter(|atomic| denied.contains(atomic) && allowed.contains(atomic))
+                    .collect();
+
+                if masked.is_empty() {
+                    continue;
+                }
+                masked.sort();
+
+                masked_grants.push(MaskedGrant {
+                    grant_id: grant.statement_id.clone(),
+                    deny_id: deny.statement_id.clone(),
+                    masked,
+                });
+            }
+        }
+
+        masked_grants
     }
 }
 
-#[derive(Debug, Clone)]
+// a resolved statement as stored in the statement log
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Permission {
-    // WIP
+    // stable id derived from the sequence, e.g. stmt-000001
+    pub statement_id: String,
+    // position of the statement in the log, statements are applied in this order
+    pub sequence: u64,
+    pub command_raw: String,
+    pub database_operation: DatabaseOperationType,
+    // parsed expressions are kept so the statement can be re-resolved later
+    pub asset_set_expr: SetExpr,
+    pub user_set_expr: SetExpr,
+    // sets as resolved against the registries when the statement was applied
+    pub asset_set_affected: HashSet<String>,
+    pub user_set_affected: HashSet<String>,
+    // window in which the statement is in effect, only GRANT statements can be bounded
+    pub validity: Validity,
+    pub metadata: CommandParseResultMetadata,
 }
 
-#[derive(Debug, Clone)]
+impl Permission {
+    pub fn new(sequence: u64, command: CommandParseResult) -> Permission {
+        Permission {
+            statement_id: format!("stmt-{:06}", sequence),
+            sequence,
+            command_raw: command.command_raw,
+            database_operation: command.database_operation,
+            asset_set_expr: command.asset_set_expr,
+            user_set_expr: command.user_set_expr,
+            asset_set_affected: command.asset_set_affected,
+            user_set_affected: command.user_set_affected,
+            validity: command.validity,
+            metadata: command.metadata,
+        }
+    }
+
+    // re-resolves the stored expressions, e.g. against refreshed or historical registries
+    // keys that have since left a registry match nothing, the recorded sets are not kept
+    // the metadata records which registry loads the sets were resolved against
+    // sub-expressions shared with other statements are taken from the caches
+    pub fn resolve_against(
+        &mut self,
+        asset_registry: &SetRegistry,
+        asset_cache: &mut SetCache,
+        user_registry: &SetRegistry,
+        user_cache: &mut SetCache,
+    ) {
+        let asset_set = asset_cache.evaluate(&self.asset_set_expr, asset_registry);
+        let user_set = user_cache.evaluate(&self.user_set_expr, user_registry);
+        self.store_resolved(&asset_set, &user_set);
+    }
+
+    // stores sets re-resolved from the statement's expressions, with the registry loads used
+    // the registry loads recorded when the command was received are kept
+    pub fn store_resolved(&mut self, asset_set: &ResolvedSet, user_set: &ResolvedSet) {
+        self.asset_set_affected = asset_set.to_names();
+        self.user_set_affected = user_set.to_names();
+        self.metadata.last_resolved_asset_etl_datetime = Some(asset_set.registry().etl_datetime);
+        self.metadata.last_resolved_user_etl_datetime = Some(user_set.registry().etl_datetime);
+    }
+
+    // expands the statement into its (user, asset, permission) triples
+    pub fn atomic_permissions(&self) -> impl Iterator<Item = AtomicPermission> + '_ {
+        self.atomic_permissions_in(MatrixScope::All)
+    }
+
+    // only the triples within the scope, a single user or asset is looked up rather than scanned
+    // and several are intersected with the resolved set
+    pub fn atomic_permissions_in<'a>(
+        &'a self,
+        scope: MatrixScope<'a>,
+    ) -> impl Iterator<Item = AtomicPermission> + 'a {
+        let permissions = self.database_operation.permissions();
+
+        let user_ids: Box<dyn Iterator<Item = &String>> = match scope {
+            MatrixScope::User(user_id) => Box::new(self.user_set_affected.get(user_id).into_iter()),
+            MatrixScope::Users(user_ids) => Box::new(self.user_set_affected.intersection(user_ids)),
+            _ => Box::new(self.user_set_affected.iter()),
+        };
+
+        user_ids.flat_map(move |user_id| {
+            let asset_ids: Box<dyn Iterator<Item = &String>> = match scope {
+                MatrixScope::Asset(asset_id) => {
+                    Box::new(self.asset_set_affected.get(asset_id).into_iter())
+                }
+                MatrixScope::Assets(asset_ids) => {
+                    Box::new(self.asset_set_affected.intersection(asset_ids))
+                }
+                _ => Box::new(self.asset_set_affected.iter()),
+            };
+
+            asset_ids.flat_map(move |asset_id| {
+                permissions.iter().map(move |permission| AtomicPermission {
+                    user_id: user_id.clone(),
+                    asset_id: asset_id.clone(),
+                    permission: permission.clone(),
+                })
+            })
+        })
+    }
+}
+
+// set keys each stored statement depends on
+// a membership change to a key only touches the statements listed against it
+#[derive(Debug, Clone, Default)]
+pub struct StatementDependencies {
+    // key maps to the ids of the statements whose expression references it
+    pub asset_keys: HashMap<String, HashSet<String>>,
+    pub user_keys: HashMap<String, HashSet<String>>,
+    // statements using ALL or NOT, which also depend on the ids of the registry
+    pub asset_ids: HashSet<String>,
+    pub user_ids: HashSet<String>,
+}
+
+impl StatementDependencies {
+    pub fn new(statement_log: &HashMap<String, Permission>) -> StatementDependencies {
+        let mut dependencies = StatementDependencies::default();
+
+        for (statement_id, statement) in statement_log {
+            for key in statement.asset_set_expr.keys() {
+                dependencies
+                    .asset_keys
+                    .entry(key.to_string())
+                    .or_default()
+                    .insert(statement_id.clone());
+            }
+            for key in statement.user_set_expr.keys() {
+                dependencies
+                    .user_keys
+                    .entry(key.to_string())
+                    .or_default()
+                    .insert(statement_id.clone());
+            }
+            if statement.asset_set_expr.depends_on_ids() {
+                dependencies.asset_ids.insert(statement_id.clone());
+            }
+            if statement.user_set_expr.depends_on_ids() {
+                dependencies.user_ids.insert(statement_id.clone());
+            }
+        }
+
+        dependencies
+    }
+}
+
+// a single (user, asset, permission) triple
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct AtomicPermission {
+    pub user_id: String,
+    pub asset_id: String,
+    pub permission: String,
+}
+
+impl fmt::Display for AtomicPermission {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} {} on {}",
+            self.user_id, self.permission, self.asset_id
+        )
+    }
+}
+
+impl fmt::Display for Permission {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Permission {{\n\n")?;
+        write!(f, "statement_id: {},\n\n", self.statement_id)?;
+        write!(f, "command_raw: {},\n\n", self.command_raw)?;
+        write!(f, "database_operation: {:?},\n\n", self.database_operation)?;
+        write!(f, "asset_set_affected: {:?},\n\n", self.asset_set_affected)?;
+        write!(f, "user_set_affected: {:?},\n\n", self.user_set_affected)?;
+        write!(f, "validity: {},\n\n", self.validity)?;
+        write!(f, "metadata: {},\n\n", self.metadata)?;
+        write!(f, "}}")
+    }
+}
+
+// validity window of a statement, unbounded on either side when None
+// valid_from is inclusive and valid_until is exclusive
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub struct Validity {
+    pub valid_from: Option<DateTime<Utc>>,
+    pub valid_until: Option<DateTime<Utc>>,
+}
+
+impl Validity {
+    pub fn contains(&self, at: DateTime<Utc>) -> bool {
+        self.valid_from.is_none_or(|valid_from| valid_from <= at)
+            && self.valid_until.is_none_or(|valid_until| at < valid_until)
+    }
+
+    pub fn is_bounded(&self) -> bool {
+        self.valid_from.is_some() || self.valid_until.is_some()
+    }
+}
+
+impl fmt::Display for Validity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match (self.valid_from, self.valid_until) {
+            (None, None) => write!(f, "always"),
+            (Some(valid_from), None) => write!(f, "from {}", valid_from.to_rfc3339()),
+            (None, Some(valid_until)) => write!(f, "until {}", valid_until.to_rfc3339()),
+            (Some(valid_from), Some(valid_until)) => write!(
+                f,
+                "from {} until {}",
+                valid_from.to_rfc3339(),
+                valid_until.to_rfc3339()
+            ),
+        }
+    }
+}
+
+// atomic permissions granted by one statement but masked by a DENY statement
+#[derive(Debug, Clone, PartialEq)]
+pub struct MaskedGrant {
+    pub grant_id: String,
+    pub deny_id: String,
+    pub masked: Vec<AtomicPermission>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(from = "SetRegistryData", into = "SetRegistryData")]
 pub struct SetRegistry {
+    // ids are interned into dense handles so that sets can be stored as compressed bitmaps
+    // a handle is the position of the id in names and is never reused
+    names: Vec<String>,
+    handles: HashMap<String, u32>,
     // this data representation is a bit like an inverted index
-    // role maps to the set of IDs that hold that role
-    pub data: HashMap<String, HashSet<String>>,
-    // for convenience we also store the list of ids
-    pub ids: HashSet<String>,
+    // role maps to the bitmap of IDs that hold that role
+    data: HashMap<String, RoaringBitmap>,
+    // for convenience we also store the bitmap of ids
+    ids: RoaringBitmap,
+    // changes whenever the membership changes, unique across registries
+    // so a clone that is then modified never shares a version with the original
+    version: u64,
     pub etl_datetime: DateTime<Utc>,
 }
 
+fn next_registry_version() -> u64 {
+    static VERSION: AtomicU64 = AtomicU64::new(0);
+    VERSION.fetch_add(1, Ordering::Relaxed)
+}
+
+// the serialized form of a registry, ids are written by name so handles stay internal
+#[derive(Serialize, Deserialize)]
+struct SetRegistryData {
+    data: HashMap<String, HashSet<String>>,
+    ids: HashSet<String>,
+    etl_datetime: DateTime<Utc>,
+}
+
+impl From<SetRegistryData> for SetRegistry {
+    fn from(registry_data: SetRegistryData) -> Self {
+        let mut registry = SetRegistry::new();
+        for id in &registry_data.ids {
+            registry.insert_id(id);
+        }
+        for (key, members) in registry_data.data {
+            for id in members {
+                registry.insert(key.clone(), id);
+            }
+        }
+        registry.etl_datetime = registry_data.etl_datetime;
+        registry
+    }
+}
+
+impl From<SetRegistry> for SetRegistryData {
+    fn from(registry: SetRegistry) -> Self {
+        SetRegistryData {
+            data: registry
+                .data
+                .iter()
+                .map(|(key, members)| (key.clone(), registry.names_of(members)))
+                .collect(),
+            ids: registry.names_of(&registry.ids),
+            etl_datetime: registry.etl_datetime,
+        }
+    }
+}
+
+impl Default for SetRegistry {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// the ids are taken to be every member of every set
+impl From<HashMap<String, HashSet<String>>> for SetRegistry {
+    fn from(data: HashMap<String, HashSet<String>>) -> Self {
+        let mut registry = SetRegistry::new();
+        for (key, members) in data {
+            for id in members {
+                registry.insert_id(&id);
+                registry.insert(key.clone(), id);
+            }
+        }
+        registry
+    }
+}
+
+// registries are equal when they hold the same ids and memberships, whatever the handles
+impl PartialEq for SetRegistry {
+    fn eq(&self, other: &Self) -> bool {
+        self.etl_datetime == other.etl_datetime
+            && self.ids().collect::<HashSet<_>>() == other.ids().collect::<HashSet<_>>()
+            && self.data.len() == other.data.len()
+            && self
+                .keys()
+                .all(|key| other.contains_key(key) && self.members(key) == other.members(key))
+    }
+}
+
 impl SetRegistry {
     pub fn new() -> SetRegistry {
         SetRegistry {
+            names: Vec::new(),
+            handles: HashMap::new(),
             data: HashMap::new(),
-            ids: HashSet::new(),
+            ids: RoaringBitmap::new(),
+            version: next_registry_version(),
             etl_datetime: Utc::now(),
         }
     }
 
+    pub fn version(&self) -> u64 {
+        self.version
+    }
+
+    // registers an id, returns false if it was already registered
+    pub fn insert_id(&mut self, id: &str) -> bool {
+        let handle = self.intern(id);
+        let inserted = self.ids.insert(handle);
+        if inserted {
+            self.version = next_registry_version();
+        }
+        inserted
+    }
+
     pub fn insert(&mut self, k: String, v: String) {
-        self.data
-            .entry(k.clone())
-            .or_insert(HashSet::new())
-            .insert(v);
+        let handle = self.intern(&v);
+        if self.data.entry(k).or_default().insert(handle) {
+            self.version = next_registry_version();
+        }
     }
 
-    pub fn delete(&mut self, k: &String, v: &String) {
-        if let Some(values) = self.data.get_mut(k) {
-            values.remove(v);
-            if values.is_empty() {
+    pub fn delete(&mut self, k: &str, v: &str) {
+        let Some(handle) = self.handle(v) else {
+            return;
+        };
+        if let Some(members) = self.data.get_mut(k) {
+            if members.remove(handle) {
+                self.version = next_registry_version();
+            }
+            if members.is_empty() {
                 self.data.remove(k);
             }
         }
     }
+
+    // unregisters an id that holds no key, its handle is kept and never reused
+    pub fn remove_id(&mut self, id: &str) -> bool {
+        let Some(handle) = self.handle(id) else {
+            return false;
+        };
+        let removed = self.ids.remove(handle);
+        if removed {
+            self.version = next_registry_version();
+        }
+        removed
+    }
+
+    pub fn contains_key(&self, key: &str) -> bool {
+        self.data.contains_key(key)
+    }
+
+    pub fn keys(&self) -> impl Iterator<Item = &String> {
+        self.data.keys()
+    }
+
+    pub fn contains_id(&self, id: &str) -> bool {
+        self.handle(id)
+            .is_some_and(|handle| self.ids.contains(handle))
+    }
+
+    pub fn id_count(&self) -> usize {
+        self.ids.len() as usize
+    }
+
+    pub fn ids(&self) -> impl Iterator<Item = &str> {
+        self.ids.iter().map(|handle| self.name(handle))
+    }
+
+    pub fn is_member(&self, key: &str, id: &str) -> bool {
+        match (self.data.get(key), self.handle(id)) {
+            (Some(members), Some(handle)) => members.contains(handle),
+            _ => false,
+        }
+    }
+
+    // members of a key by name, empty if the key does not exist
+    pub fn members(&self, key: &str) -> HashSet<&str> {
+        self.data
+            .get(key)
+            .into_iter()
+            .flatten()
+            .map(|handle| self.name(handle))
+            .collect()
+    }
+
+    // bitmap of the handles holding a key
+    pub fn key_bitmap(&self, key: &str) -> Option<&RoaringBitmap> {
+        self.data.get(key)
+    }
+
+    // bitmap of the handles of every registered id
+    pub fn id_bitmap(&self) -> &RoaringBitmap {
+        &self.ids
+    }
+
+    pub fn handle(&self, id: &str) -> Option<u32> {
+        self.handles.get(id).copied()
+    }
+
+    // panics if the handle was not issued by this registry
+    // only called with handles taken from the registry's own bitmaps
+    pub(crate) fn name(&self, handle: u32) -> &str {
+        &self.names[handle as usize]
+    }
+
+    // as name, for a bitmap of handles taken from the registry
+    pub(crate) fn names_of(&self, handles: &RoaringBitmap) -> HashSet<String> {
+        handles
+            .iter()
+            .map(|handle| self.name(handle).to_string())
+            .collect()
+    }
+
+    fn intern(&mut self, id: &str) -> u32 {
+        if let Some(handle) = self.handle(id) {
+            return handle;
+        }
+        let handle = self.names.len() as u32;
+        self.names.push(id.to_string());
+        self.handles.insert(id.to_string(), handle);
+        handle
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{error::RhubarbError, test_support::datetime};
+
+    fn test_database() -> Database {
+        let mut asset_registry = SetRegistry::new();
+        for (key, id) in [("schema:tax", "tax.returns"), ("schema:tax", "tax.audit")] {
+            asset_registry.insert_id(id);
+            asset_registry.insert(key.to_string(), id.to_string());
+        }
+        asset_registry.insert("table:audit".to_string(), "tax.audit".to_string());
+
+        let mut user_registry = SetRegistry::new();
+        for (key, id) in [("department:tax", "alice"), ("department:tax", "bob")] {
+            user_registry.insert_id(id);
+            user_registry.insert(key.to_string(), id.to_string());
+        }
+        user_registry.insert("designation:intern".to_string(), "bob".to_string());
+
+        Database {
+            asset_registry,
+            user_registry,
+            asset_registry_history: Vec::new(),
+            user_registry_history: Vec::new(),
+            valid_permissions: vec!["READ".to_string(), "WRITE".to_string()],
+            permission_hierarchy: PermissionHierarchy::default(),
+            statement_log: HashMap::new(),
+            access_matrix: AccessMatrix::new(),
+            dependencies: StatementDependencies::default(),
+            log_sequence: 0,
+        }
+    }
+
+    fn atomic(user_id: &str, asset_id: &str, permission: &str) -> AtomicPermission {
+        AtomicPermission {
+            user_id: user_id.to_string(),
+            asset_id: asset_id.to_string(),
+            permission: permission.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_revoke_withdraws_granted_permissions() {
+        // arrange
+        let mut database = test_database();
+
+        // act
+        database
+            .apply_command("GRANT READ ON schema:tax TO department:tax")
+            .unwrap();
+        database
+            .apply_command("REVOKE READ ON schema:tax FROM designation:intern")
+            .unwrap();
+        let effective = database.effective_permissions();
+
+        // assert
+        assert_eq!(database.statement_log.len(), 2);
+        assert_eq!(
+            effective,
+            HashSet::from([
+                atomic("alice", "tax.returns", "READ"),
+                atomic("alice", "tax.audit", "READ"),
+            ])
+        );
+    }
+
+    #[test]
+    fn test_grant_after_revoke_is_effective() {
+        // arrange
+        let mut database = test_database();
+
+        // act
+        database
+            .apply_command("REVOKE READ ON schema:tax FROM department:tax")
+            .unwrap();
+        database
+            .apply_command("GRANT READ ON schema:tax TO designation:intern")
+            .unwrap();
+
+        // assert
+        assert_eq!(database.effective_permissions().len(), 2);
+    }
+
+    #[test]
+    fn test_deny_overrides_later_grant() {
+        // arrange
+        let mut database = test_database();
+
+        // act
+        database
+            .apply_command("DENY READ ON schema:tax TO designation:intern")
+            .unwrap();
+        database
+            .apply_command("GRANT READ ON schema:tax TO department:tax")
+            .unwrap();
+        let effective = database.effective_permissions();
+
+        // assert
+        assert_eq!(
+            effective,
+            HashSet::from([
+                atomic("alice", "tax.returns", "READ"),
+                atomic("alice", "tax.audit", "READ"),
+            ])
+        );
+    }
+
+    #[test]
+    fn test_deny_is_scoped_to_permission() {
+        let mut database = test_database();
+
+        database
+            .apply_command("DENY WRITE ON schema:tax TO designation:intern")
+            .unwrap();
+        database
+            .apply_command("GRANT READ ON schema:tax TO designation:intern")
+            .unwrap();
+
+        assert_eq!(database.effective_permissions().len(), 2);
+    }
+
+    #[test]
+    fn test_masked_grants_report() {
+        // arrange
+        let mut database = test_database();
+        let grant_id = database
+            .apply_command("GRANT READ ON schema:tax TO department:tax")
+            .unwrap();
+        let deny_id = database
+            .apply_command("DENY READ ON schema:tax TO designation:intern")
+            .unwrap();
+
+        // act
+        let masked_grants = database.masked_grants();
+
+        // assert
+        assert_eq!(
+            masked_grants,
+            vec![MaskedGrant {
+                grant_id,
+                deny_id,
+                masked: vec![
+                    atomic("bob", "tax.audit", "READ"),
+                    atomic("bob", "tax.returns", "READ"),
+                ],
+            }]
+        );
+    }
+
+    #[test]
+    fn test_access_matrix_lookup() {
+        // arrange
+        let mut database = test_database();
+
+        // act
+        database
+            .apply_command("GRANT READ ON schema:tax TO department:tax")
+            .unwrap();
+        database
+            .apply_command("GRANT WRITE ON schema:tax TO designation:intern")
+            .unwrap();
+        database
+            .apply_command("DENY READ ON schema:tax TO designation:intern")
+            .unwrap();
+
+        // assert
+        assert_eq!(database.access_matrix.len(), 4);
+        assert!(database.check("alice", "tax.audit", "READ"));
+        assert!(!database.check("alice", "tax.audit", "WRITE"));
+        assert!(!database.check("bob", "tax.audit", "READ"));
+        assert!(!database.check("carol", "tax.audit", "READ"));
+        assert_eq!(
+            database.permissions_for("bob", "tax.returns"),
+            HashSet::from(["WRITE".to_string()])
+        );
+        assert!(database.permissions_for("bob", "unknown").is_empty());
+    }
+
+    #[test]
+    fn test_apply_command_stores_statement() {
+        // arrange
+        let mut database = test_database();
+
+        // act
+        let first_id = database
+            .apply_command("GRANT READ ON schema:tax TO department:tax")
+            .unwrap();
+        let second_id = database
+            .apply_command("REVOKE READ ON schema:tax FROM designation:intern")
+            .unwrap();
+
+        // assert
+        assert_eq!(first_id, "stmt-000001");
+        assert_eq!(second_id, "stmt-000002");
+
+        let statement = &database.statement_log[&first_id];
+        assert_eq!(statement.sequence, 1);
+        assert_eq!(
+            statement.command_raw,
+            "GRANT READ ON schema:tax TO department:tax"
+        );
+        assert_eq!(
+            statement.database_operation,
+            DatabaseOperationType::Grant(vec!["READ".to_string()])
+        );
+        assert_eq!(
+            statement.user_set_expr,
+            SetExpr::Set("department:tax".to_string())
+        );
+        assert_eq!(
+            statement.user_set_affected,
+            HashSet::from(["alice".to_string(), "bob".to_string()])
+        );
+    }
+
+    #[test]
+    fn test_grant_multiple_permissions() {
+        // arrange
+        let mut database = test_database();
+
+        // act
+        let statement_id = database
+            .apply_command("GRANT READ, WRITE ON schema:tax TO designation:intern")
+            .unwrap();
+        database
+            .apply_command("REVOKE WRITE ON schema:tax FROM designation:intern")
+            .unwrap();
+
+        // assert
+        assert_eq!(
+            database.statement_log[&statement_id].database_operation,
+            DatabaseOperationType::Grant(vec!["READ".to_string(), "WRITE".to_string()])
+        );
+        assert_eq!(database.access_matrix.len(), 2);
+        assert!(database.check("bob", "tax.audit", "READ"));
+        assert!(!database.check("bob", "tax.audit", "WRITE"));
+    }
+
+    #[test]
+    fn test_grant_all_permissions() {
+        let mut database = test_database();
+
+        let statement_id = database
+            .apply_command("GRANT ALL ON schema:tax TO designation:intern")
+            .unwrap();
+
+        assert_eq!(
+            database.statement_log[&statement_id].database_operation,
+            DatabaseOperationType::Grant(vec!["READ".to_string(), "WRITE".to_string()])
+        );
+        assert_eq!(
+            database.permissions_for("bob", "tax.returns"),
+            HashSet::from(["READ".to_string(), "WRITE".to_string()])
+        );
+    }
+
+    #[test]
+    fn test_grant_rejects_any_invalid_permission() {
+        let mut database = test_database();
+
+        assert_eq!(
+            database.apply_command("GRANT READ, DELETE ON schema:tax TO department:tax"),
+            Err(RhubarbError::InvalidPermission("DELETE".to_string()))
+        );
+        assert_eq!(
+            database.apply_command("GRANT ALL, BOGUS ON schema:tax TO department:tax"),
+            Err(RhubarbError::InvalidPermission("BOGUS".to_string()))
+        );
+        assert!(database.statement_log.is_empty());
+    }
+
+    #[test]
+    fn test_implied_permissions() {
+        // arrange
+        let mut database = test_database();
+        database.permission_hierarchy = PermissionHierarchy::new(
+            HashMap::from([("WRITE".to_string(), vec!["READ".to_string()])]),
+            &database.valid_permissions,
+        )
+        .unwrap();
+
+        // act
+        database
+            .apply_command("GRANT WRITE ON schema:tax TO department:tax")
+            .unwrap();
+        database
+            .apply_command("REVOKE READ ON schema:tax FROM department:tax")
+            .unwrap();
+        database
+            .apply_command("DENY READ ON table:audit TO designation:intern")
+            .unwrap();
+
+        // assert - WRITE still implies READ after the REVOKE
+        assert!(database.check("alice", "tax.audit", "READ"));
+        assert!(database.check("alice", "tax.audit", "WRITE"));
+        // denying READ also denies WRITE, which implies it
+        assert!(database.check("bob", "tax.returns", "WRITE"));
+        assert!(!database.check("bob", "tax.audit", "READ"));
+        assert!(!database.check("bob", "tax.audit", "WRITE"));
+    }
+
+    #[test]
+    fn test_revoke_invalid_permission() {
+        let mut database = test_database();
+
+        assert_eq!(
+            database.apply_command("REVOKE DELETE ON schema:tax FROM department:tax"),
+            Err(RhubarbError::InvalidPermission("DELETE".to_string()))
+        );
+        assert!(database.statement_log.is_empty());
+    }
+
+    #[test]
+    fn test_time_bound_grant() {
+        // arrange
+        let mut database = test_database();
+
+        // act
+        let statement_id = database
+            .apply_command(
+                "GRANT READ ON schema:tax TO department:tax FROM 2024-01-01 UNTIL 2024-03-31",
+            )
+            .unwrap();
+        database
+            .apply_command("GRANT READ ON table:audit TO designation:intern UNTIL 2024-02-01")
+            .unwrap();
+
+        // assert
+        assert_eq!(
+            database.statement_log[&statement_id].validity,
+            Validity {
+                valid_from: Some(datetime("2024-01-01T00:00:00Z")),
+                valid_until: Some(datetime("2024-04-01T00:00:00Z")),
+            }
+        );
+        assert!(!database.check_at(
+            "alice",
+            "tax.audit",
+            "READ",
+            datetime("2023-12-31T23:59:59Z")
+        ));
+        assert!(database.check_at(
+            "alice",
+            "tax.audit",
+            "READ",
+            datetime("2024-01-01T00:00:00Z")
+        ));
+        assert!(database.check_at(
+            "alice",
+            "tax.audit",
+            "READ",
+            datetime("2024-03-31T23:59:59Z")
+        ));
+        assert!(!database.check_at(
+            "alice",
+            "tax.audit",
+            "READ",
+            datetime("2024-04-01T00:00:00Z")
+        ));
+        assert!(!database.check("alice", "tax.audit", "READ"));
+
+        // the intern is covered by both grants, the windows are merged
+        assert!(database.check_at("bob", "tax.audit", "READ", datetime("2023-06-01T00:00:00Z")));
+        assert!(database.check_at("bob", "tax.audit", "READ", datetime("2024-03-01T00:00:00Z")));
+        assert!(!database.check_at(
+            "bob",
+            "tax.returns",
+            "READ",
+            datetime("2023-06-01T00:00:00Z")
+        ));
+        assert_eq!(
+            database.export_access(datetime("2023-06-01T00:00:00Z")),
+            vec![atomic("bob", "tax.audit", "READ")]
+        );
+        assert_eq!(
+            database.permissions_for_at("bob", "tax.returns", datetime("2024-02-01T00:00:00Z")),
+            HashSet::from(["READ".to_string()])
+        );
+    }
+
+    #[test]
+    fn test_revoke_applies_to_time_bound_grant() {
+        let mut database = test_database();
+
+        database
+            .apply_command("GRANT READ ON schema:tax TO department:tax")
+            .unwrap();
+        database
+            .apply_command("GRANT WRITE ON schema:tax TO department:tax UNTIL 2024-03-31")
+            .unwrap();
+        database
+            .apply_command("REVOKE READ, WRITE ON schema:tax FROM designation:intern")
+            .unwrap();
+
+        // the REVOKE is timeless, so it also applies at a time before it was received
+        let at = datetime("2024-02-01T00:00:00Z");
+        assert!(database.check_at("alice", "tax.audit", "WRITE", at));
+        assert!(!database.check_at("bob", "tax.audit", "WRITE", at));
+        assert!(!database.check_at("bob", "tax.audit", "READ", at));
+        assert!(database.check("alice", "tax.audit", "READ"));
+        assert!(!database.check("alice", "tax.audit", "WRITE"));
+    }
+
+    #[test]
+    fn test_empty_validity_window() {
+        let mut database = test_database();
+
+        let result = database.apply_command(
+            "GRANT READ ON schema:tax TO department:tax FROM 2024-04-01 UNTIL 2024-03-31",
+        );
+
+        assert!(matches!(
+            result,
+            Err(RhubarbError::EmptyValidityWindow { .. })
+        ));
+        assert!(database.statement_log.is_empty());
+    }
+
+    #[test]
+    fn test_statement_metadata() {
+        // arrange
+        let mut database = test_database();
+        let submission = Submission::new("carol")
+            .with_ticket("SEC-1234")
+            .with_justification("quarterly audit");
+        let before = Utc::now();
+
+        // act
+        let statement_id = database
+            .apply_command_as("GRANT READ ON schema:tax TO department:tax", &submission)
+            .unwrap();
+
+        // assert
+        let metadata = &database.statement_log[&statement_id].metadata;
+        assert_eq!(metadata.submitted_by, "carol");
+        assert_eq!(metadata.ticket.as_deref(), Some("SEC-1234"));
+        assert_eq!(metadata.justification.as_deref(), Some("quarterly audit"));
+        assert!(metadata.command_received_datetime >= before);
+        assert_eq!(
+            metadata.asset_registry_etl_datetime,
+            database.asset_registry.etl_datetime
+        );
+        assert_eq!(
+            metadata.user_registry_etl_datetime,
+            database.user_registry.etl_datetime
+        );
+        assert_eq!(metadata.last_resolved_asset_etl_datetime, None);
+        assert_eq!(metadata.last_resolved_user_etl_datetime, None);
+    }
 }
@@ -1,5 +1,8 @@
+use crate::access_matrix::AccessMatrix;
 use crate::config::Config;
-use crate::database::{Database, SetRegistry};
+use crate::database::{Database, SetRegistry, StatementDependencies};
+use crate::error::{Result, RhubarbError};
+use crate::permission_hierarchy::PermissionHierarchy;
 use std::fs::File;
 use std::{collections::HashMap, path::Path};
 
@@ -7,25 +10,54 @@ pub fn new_database_from_files(
     config_filepath: &Path,
     asset_csv_filepath: &Path,
     users_csv_filepath: &Path,
-) -> anyhow::Result<Database> {
-    let config_toml = std::fs::read_to_string(config_filepath)?;
-    let config: Config = toml::from_str(&config_toml)?;
+) -> Result<Database> {
+    let config_toml = std::fs::read_to_string(config_filepath)
+        .map_err(|err| config_error(config_filepath, err))?;
+    let config: Config =
+        toml::from_str(&config_toml).map_err(|err| config_error(config_filepath, err))?;
+
+    let permission_hierarchy = PermissionHierarchy::new(
+        config.database_config.permission_implications,
+        &config.database_config.valid_permissions,
+    )?;
 
     let db = Database {
         asset_registry: load_set_registry_from_csv(asset_csv_filepath)?,
         user_registry: load_set_registry_from_csv(users_csv_filepath)?,
+        asset_registry_history: Vec::new(),
+        user_registry_history: Vec::new(),
         valid_permissions: config.database_config.valid_permissions,
+        permission_hierarchy,
         statement_log: HashMap::new(),
+        access_matrix: AccessMatrix::new(),
+        dependencies: StatementDependencies::default(),
+        log_sequence: 0,
     };
 
-    anyhow::Ok(db)
+    Ok(db)
 }
 
-fn load_set_registry_from_csv(csv_filepath: &Path) -> anyhow::Result<SetRegistry> {
-    let file = File::open(csv_filepath)?;
+fn config_error(path: &Path, err: impl ToString) -> RhubarbError {
+    RhubarbError::Config {
+        path: path.to_path_buf(),
+        message: err.to_string(),
+    }
+}
+
+fn registry_error(path: &Path, err: impl ToString) -> RhubarbError {
+    RhubarbError::RegistryLoad {
+        path: path.to_path_buf(),
+        message: err.to_string(),
+    }
+}
+
+pub fn load_set_registry_from_csv(csv_filepath: &Path) -> Result<SetRegistry> {
+    let file = File::open(csv_filepath).map_err(|err| registry_error(csv_filepath, err))?;
     let mut csv_rdr = csv::ReaderBuilder::new().delimiter(b',').from_reader(file);
 
-    let headers = csv_rdr.headers()?;
+    let headers = csv_rdr
+        .headers()
+        .map_err(|err| registry_error(csv_filepath, err))?;
     let transformed_headers: Vec<String> = headers
         .iter()
         .map(|header| {
@@ -36,8 +68,8 @@ fn load_set_registry_from_csv(csv_filepath: &Path) -> anyhow::Result<SetRegistry
         })
         .collect();
 
-    if transformed_headers.get(0) != Some(&"id".to_string()) {
-        return Err(anyhow::anyhow!("First column is not 'id'"));
+    if transformed_headers.first() != Some(&"id".to_string()) {
+        return Err(registry_error(csv_filepath, "First column is not 'id'"));
     }
 
     // process rows by processing each entry
@@ -45,10 +77,19 @@ fn load_set_registry_from_csv(csv_filepath: &Path) -> anyhow::Result<SetRegistry
 
     while let Some(result) = csv_rdr.records().next() {
         // extract the row id
-        let record = result?;
-        let id = record.get(0).unwrap().to_string();
+        let record = result.map_err(|err| registry_error(csv_filepath, err))?;
+        let id = match record.get(0).map(|id| id.trim()) {
+            Some(id) if !id.is_empty() => id.to_string(),
+            _ => {
+                let line = record.position().map_or(0, |position| position.line());
+                return Err(registry_error(
+                    csv_filepath,
+                    format!("Row on line {} has an empty id", line),
+                ));
+            }
+        };
 
-        registry.ids.insert(id.clone());
+        registry.insert_id(&id);
 
         // iterate through each entry in the row
         // additionally zip this with the headers for key creation
@@ -67,5 +108,110 @@ fn load_set_registry_from_csv(csv_filepath: &Path) -> anyhow::Result<SetRegistry
             })
     }
 
-    anyhow::Ok(registry)
+    Ok(registry)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::temp_path;
+    use std::path::PathBuf;
+
+    // writes the contents to a uniquely named file in the temp directory
+    fn write_temp_file(name: &str, extension: &str, contents: &str) -> PathBuf {
+        let path = temp_path(&format!("{}.{}", name, extension));
+        std::fs::write(&path, contents).unwrap();
+        path
+    }
+
+    fn assert_registry_load_error(path: &Path) {
+        match load_set_registry_from_csv(path) {
+            Err(RhubarbError::RegistryLoad { path: err_path, .. }) => assert_eq!(err_path, path),
+            other => panic!("Expected a registry load error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_load_registry_from_csv() {
+        let path = write_temp_file("valid", "csv", "id,Department\na,Tax\nb,Audit (Internal)\n");
+
+        let registry = load_set_registry_from_csv(&path).unwrap();
+
+        assert_eq!(registry.id_count(), 2);
+        assert!(registry.is_member("department:tax", "a"));
+        assert!(registry.is_member("department:audit__internal_", "b"));
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_load_registry_missing_file() {
+        assert_registry_load_error(Path::new("mock_data/does_not_exist.csv"));
+    }
+
+    #[test]
+    fn test_load_registry_first_column_not_id() {
+        let path = write_temp_file("no_id", "csv", "name,department\na,tax\n");
+        assert_registry_load_error(&path);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_load_registry_empty_file() {
+        let path = write_temp_file("empty", "csv", "");
+        assert_registry_load_error(&path);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_load_registry_ragged_row() {
+        let path = write_temp_file("ragged", "csv", "id,department\na,tax\nb\n");
+        assert_registry_load_error(&path);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_load_registry_empty_id() {
+        let path = write_temp_file("empty_id", "csv", "id,department\na,tax\n ,audit\n");
+        assert_registry_load_error(&path);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_new_database_cyclic_permission_implications() {
+        let path = write_temp_file(
+            "cyclic_config",
+            "toml",
+            "[database_config]\nvalid_permissions = [\"READ\", \"WRITE\"]\n\n[database_config.permission_implications]\nREAD = [\"WRITE\"]\nWRITE = [\"READ\"]\n",
+        );
+
+        let result = new_database_from_files(
+            &path,
+            Path::new("mock_data/assets.csv"),
+            Path::new("mock_data/employees.csv"),
+        );
+
+        assert!(matches!(
+            result,
+            Err(RhubarbError::CyclicPermissionImplication { .. })
+        ));
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_new_database_bad_config() {
+        let path = write_temp_file(
+            "bad_config",
+            "toml",
+            "[database_config]\nvalid_permissions = 1\n",
+        );
+
+        let result = new_database_from_files(
+            &path,
+            Path::new("mock_data/assets.csv"),
+            Path::new("mock_data/employees.csv"),
+        );
+
+        assert!(matches!(result, Err(RhubarbError::Config { .. })));
+        std::fs::remove_file(path).unwrap();
+    }
 }
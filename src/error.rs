@@ -0,0 +1,260 @@
+use std::{fmt, path::PathBuf};
+
+use chrono::{DateTime, Utc};
+
+use nom::{
+    error::{ErrorKind, ParseError as NomParseError},
+    IResult,
+};
+
+// nom error type used by the command and set parsers
+// records the furthest point reached and the tokens that would have been accepted there
+#[derive(Debug, Clone, PartialEq)]
+pub struct SyntaxError<'a> {
+    // remaining input at the point of failure
+    pub input: &'a str,
+    pub expected: Vec<&'static str>,
+}
+
+pub type ParseResult<'a, O> = IResult<&'a str, O, SyntaxError<'a>>;
+
+impl<'a> NomParseError<&'a str> for SyntaxError<'a> {
+    fn from_error_kind(input: &'a str, _kind: ErrorKind) -> Self {
+        SyntaxError {
+            input,
+            expected: Vec::new(),
+        }
+    }
+
+    fn append(_input: &'a str, _kind: ErrorKind, other: Self) -> Self {
+        other
+    }
+
+    // keep whichever alternative got further, merging expectations on a tie
+    fn or(mut self, other: Self) -> Self {
+        match self.input.len().cmp(&other.input.len()) {
+            std::cmp::Ordering::Less => self,
+            std::cmp::Ordering::Greater => other,
+            std::cmp::Ordering::Equal => {
+                for token in other.expected {
+                    if !self.expected.contains(&token) {
+                        self.expected.push(token);
+                    }
+                }
+                self
+            }
+        }
+    }
+}
+
+// labels the tokens a parser accepts, used when the failure has no expectations of its own
+// or when the parser failed without consuming any input
+pub fn expect<'a, O, F>(
+    expected: &'static [&'static str],
+    mut parser: F,
+) -> impl FnMut(&'a str) -> ParseResult<'a, O>
+where
+    F: nom::Parser<&'a str, O, SyntaxError<'a>>,
+{
+    move |input: &'a str| {
+        parser.parse(input).map_err(|err| {
+            err.map(|err| {
+                if err.expected.is_empty() || err.input.len() >= input.len() {
+                    SyntaxError {
+                        input,
+                        expected: expected.to_vec(),
+                    }
+                } else {
+                    err
+                }
+            })
+        })
+    }
+}
+
+// a parse failure located in the original input
+#[derive(Debug, Clone, PartialEq)]
+pub struct ParseError {
+    // byte offset into the original input
+    pub offset: usize,
+    // line and column are 1-based, column counts characters
+    pub line: usize,
+    pub column: usize,
+    pub expected: Vec<String>,
+    // the token found at the offset, or "end of input"
+    pub found: String,
+    // the offending line of the original input with a caret under the offset
+    pub snippet: String,
+}
+
+impl ParseError {
+    pub fn new(original: &str, err: SyntaxError) -> ParseError {
+        // point at the next token rather than the whitespace before it
+        // the error input is always a suffix of the original, so this is a char boundary
+        let remaining = err.input.trim_start();
+        let offset = original.len().saturating_sub(remaining.len());
+
+        let line_start = original[..offset].rfind('\n').map_or(0, |i| i + 1);
+        let line_end = original[offset..]
+            .find('\n')
+            .map_or(original.len(), |i| offset + i);
+        let line = original[..offset].matches('\n').count() + 1;
+        let column = original[line_start..offset].chars().count() + 1;
+
+        let found = match remaining.split_whitespace().next() {
+            Some(token) => format!("'{}'", token),
+            None => "end of input".to_string(),
+        };
+
+        let snippet = format!(
+            "{}\n{}^",
+            &original[line_start..line_end],
+            " ".repeat(column - 1)
+        );
+
+        ParseError {
+            offset,
+            line,
+            column,
+            expected: err.expected.iter().map(|s| s.to_string()).collect(),
+            found,
+            snippet,
+        }
+    }
+
+    // converts a nom result over the whole input into a located error
+    pub fn from_nom(original: &str, err: nom::Err<SyntaxError>) -> ParseError {
+        match err {
+            nom::Err::Error(err) | nom::Err::Failure(err) => ParseError::new(original, err),
+            // the parsers are all complete, but treat this as running out of input
+            nom::Err::Incomplete(_) => ParseError::new(
+                original,
+                SyntaxError {
+                    input: &original[original.len()..],
+                    expected: Vec::new(),
+                },
+            ),
+        }
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Syntax error at line {}, column {}: ",
+            self.line, self.column
+        )?;
+        match self.expected.as_slice() {
+            [] => write!(f, "unexpected {}", self.found)?,
+            [expected] => write!(f, "expected {}, found {}", expected, self.found)?,
+            expected => write!(
+                f,
+                "expected one of {}, found {}",
+                expected.join(", "),
+                self.found
+            )?,
+        }
+        write!(f, "\n{}", self.snippet)
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+// a key used in a set expression that does not exist in the registry
+#[derive(Debug, Clone, PartialEq)]
+pub struct UnknownKey {
+    pub key: String,
+    // nearest existing keys, best match first
+    pub suggestions: Vec<String>,
+}
+
+impl fmt::Display for UnknownKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.key)?;
+        if !self.suggestions.is_empty() {
+            write!(f, " (did you mean {}?)", self.suggestions.join(", "))?;
+        }
+        Ok(())
+    }
+}
+
+// errors returned by the public api of the library
+#[derive(Debug, Clone, PartialEq, thiserror::Error)]
+pub enum RhubarbError {
+    #[error("{0}")]
+    Parse(#[from] ParseError),
+    #[error("Key not found:{}", render_unknown_keys(.0))]
+    UnknownKeys(Vec<UnknownKey>),
+    #[error("Invalid permission type: {0}")]
+    InvalidPermission(String),
+    #[error("Permission implications contain a cycle: {}", .cycle.join(" -> "))]
+    CyclicPermissionImplication { cycle: Vec<String> },
+    #[error("Validity window is empty: FROM {valid_from} is not before UNTIL {valid_until}")]
+    EmptyValidityWindow {
+        valid_from: DateTime<Utc>,
+        valid_until: DateTime<Utc>,
+    },
+    #[error("Failed to load registry {path}: {message}")]
+    RegistryLoad { path: PathBuf, message: String },
+    #[error("Failed to load config {path}: {message}")]
+    Config { path: PathBuf, message: String },
+    #[error("Failed to save or load snapshot {path}: {message}")]
+    Snapshot { path: PathBuf, message: String },
+    #[error("Refresh plan is out of date, the database changed after it was planned")]
+    StaleRefreshPlan,
+    #[error("Write-ahead log {path} is unusable: {message}")]
+    WriteAheadLog { path: PathBuf, message: String },
+}
+
+pub type Result<T> = std::result::Result<T, RhubarbError>;
+
+fn render_unknown_keys(unknown_keys: &[UnknownKey]) -> String {
+    unknown_keys
+        .iter()
+        .map(|unknown_key| format!("\n  {}", unknown_key))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_error_location() {
+        // arrange
+        let original = "GRANT READ\nON (A OR";
+        let err = SyntaxError {
+            input: &original[original.len()..],
+            expected: vec!["set key"],
+        };
+
+        // act
+        let parse_error = ParseError::new(original, err);
+
+        // assert
+        assert_eq!(parse_error.offset, 19);
+        assert_eq!(parse_error.line, 2);
+        assert_eq!(parse_error.column, 9);
+        assert_eq!(parse_error.found, "end of input");
+        assert_eq!(parse_error.snippet, "ON (A OR\n        ^");
+        assert_eq!(
+            parse_error.to_string(),
+            "Syntax error at line 2, column 9: expected set key, found end of input\nON (A OR\n        ^"
+        );
+    }
+
+    #[test]
+    fn test_parse_error_skips_whitespace() {
+        let original = "A   B";
+        let err = SyntaxError {
+            input: &original[1..],
+            expected: vec!["OR", "end of input"],
+        };
+
+        let parse_error = ParseError::new(original, err);
+
+        assert_eq!(parse_error.offset, 4);
+        assert_eq!(parse_error.found, "'B'");
+    }
+}
@@ -0,0 +1,260 @@
+use std::fmt;
+
+use crate::{
+    database::{Database, Validity},
+    parse_command::DatabaseOperationType,
+    parse_set::{self, SetExpr},
+};
+
+// answers "why does (or doesn't) this user hold this permission on this asset"
+#[derive(Debug, Clone)]
+pub struct AccessExplanation {
+    pub user_id: String,
+    pub asset_id: String,
+    pub permission: String,
+    // outcome after deny-overrides-allow evaluation
+    pub granted: bool,
+    // every statement covering the atomic permission, in the order they were applied
+    pub statements: Vec<StatementContribution>,
+}
+
+#[derive(Debug, Clone)]
+pub struct StatementContribution {
+    pub statement_id: String,
+    pub command_raw: String,
+    pub database_operation: DatabaseOperationType,
+    pub validity: Validity,
+    // sub-expressions of the statement's sets that contain the user / asset
+    pub user_matches: Vec<SetExpr>,
+    pub asset_matches: Vec<SetExpr>,
+}
+
+impl fmt::Display for AccessExplanation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let outcome = if self.granted {
+            "GRANTED"
+        } else {
+            "NOT GRANTED"
+        };
+        writeln!(
+            f,
+            "{} {} on {}: {}",
+            self.user_id, self.permission, self.asset_id, outcome
+        )?;
+
+        for statement in &self.statements {
+            writeln!(f)?;
+            writeln!(f, "{}: {}", statement.statement_id, statement.command_raw)?;
+            if statement.validity.is_bounded() {
+                writeln!(f, "  valid {}", statement.validity)?;
+            }
+            for user_match in &statement.user_matches {
+                writeln!(f, "  user matched by {}", user_match)?;
+            }
+            for asset_match in &statement.asset_matches {
+                writeln!(f, "  asset matched by {}", asset_match)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl Database {
+    pub fn explain_access(
+        &self,
+        user_id: &str,
+        asset_id: &str,
+        permission: &str,
+    ) -> AccessExplanation {
+        // GRANT / REVOKE of a permission implying this one, or DENY of one implied by it
+        let implying = self.permission_hierarchy.implying(permission);
+        let implied = self.permission_hierarchy.implied(permission);
+
+        let mut statements = Vec::new();
+
+        for statement in self.ordered_statements() {
+            let related = match statement.database_operation {
+                DatabaseOperationType::Deny(_) => &implied,
+                _ => &implying,
+            };
+
+            if !statement
+                .database_operation
+                .permissions()
+                .iter()
+                .any(|p| related.contains(p))
+                || !statement.user_set_affected.contains(user_id)
+                || !statement.asset_set_affected.contains(asset_id)
+            {
+                continue;
+            }
+
+            statements.push(StatementContribution {
+                statement_id: statement.statement_id.clone(),
+                command_raw: statement.command_raw.clone(),
+                database_operation: statement.database_operation.clone(),
+                validity: statement.validity,
+                user_matches: parse_set::matching_subexprs(
+                    &statement.user_set_expr,
+                    user_id,
+                    &self.user_registry,
+                ),
+                asset_matches: parse_set::matching_subexprs(
+                    &statement.asset_set_expr,
+                    asset_id,
+                    &self.asset_registry,
+                ),
+            });
+        }
+
+        AccessExplanation {
+            user_id: user_id.to_string(),
+            asset_id: asset_id.to_string(),
+            permission: permission.to_string(),
+            granted: self.check(user_id, asset_id, permission),
+            statements,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::mock_database;
+
+    #[test]
+    fn test_explain_access() {
+        // arrange
+        let mut database = mock_database();
+        let grant_id = database
+            .apply_command("GRANT READ ON (schema:inventory EXCEPT table:products) TO ((designation:partner OR designation:senior) AND department:tax)")
+            .unwrap();
+        database
+            .apply_command("GRANT WRITE ON table:products TO department:tax")
+            .unwrap();
+
+        // act
+        let explanation =
+            database.explain_access("laura.martinez@example.com", "inventory.items", "READ");
+
+        // assert
+        assert!(explanation.granted);
+        assert_eq!(explanation.statements.len(), 1);
+
+        let statement = &explanation.statements[0];
+        assert_eq!(statement.statement_id, grant_id);
+
+        let user_matches: Vec<String> = statement
+            .user_matches
+            .iter()
+            .map(|expr| expr.to_string())
+            .collect();
+        assert_eq!(
+            user_matches,
+            vec![
+                "((designation:partner OR designation:senior) AND department:tax)",
+                "(designation:partner OR designation:senior)",
+                "designation:partner",
+                "department:tax",
+            ]
+        );
+
+        let asset_matches: Vec<String> = statement
+            .asset_matches
+            .iter()
+            .map(|expr| expr.to_string())
+            .collect();
+        assert_eq!(
+            asset_matches,
+            vec![
+                "(schema:inventory EXCEPT table:products)",
+                "schema:inventory"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_explain_after_key_removed_by_refresh() {
+        // arrange - a refresh empties department:tax, so the key leaves the registry
+        let mut database = mock_database();
+        database
+            .apply_command("GRANT READ ON table:items TO department:tax OR designation:partner")
+            .unwrap();
+        let mut registry = database.user_registry.clone();
+        for user_id in registry
+            .members("department:tax")
+            .into_iter()
+            .map(str::to_string)
+            .collect::<Vec<_>>()
+        {
+            registry.delete("department:tax", &user_id);
+        }
+        database.replace_user_registry(registry);
+
+        // act
+        let explanation =
+            database.explain_access("laura.martinez@example.com", "inventory.items", "READ");
+
+        // assert
+        assert!(explanation.granted);
+        let user_matches: Vec<String> = explanation.statements[0]
+            .user_matches
+            .iter()
+            .map(|expr| expr.to_string())
+            .collect();
+        assert_eq!(
+            user_matches,
+            vec![
+                "(department:tax OR designation:partner)",
+                "designation:partner"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_explain_implied_access() {
+        // arrange
+        let mut database = mock_database();
+        let grant_id = database
+            .apply_command("GRANT ADMIN ON table:items TO designation:partner")
+            .unwrap();
+        database
+            .apply_command("GRANT WRITE ON table:products TO designation:partner")
+            .unwrap();
+
+        // act
+        let explanation =
+            database.explain_access("laura.martinez@example.com", "inventory.items", "READ");
+
+        // assert
+        assert!(explanation.granted);
+        assert_eq!(explanation.statements.len(), 1);
+        assert_eq!(explanation.statements[0].statement_id, grant_id);
+    }
+
+    #[test]
+    fn test_explain_denied_access() {
+        // arrange
+        let mut database = mock_database();
+        database
+            .apply_command("GRANT READ ON schema:inventory TO department:tax")
+            .unwrap();
+        let deny_id = database
+            .apply_command("DENY READ ON table:items TO designation:partner")
+            .unwrap();
+
+        // act
+        let explanation =
+            database.explain_access("laura.martinez@example.com", "inventory.items", "READ");
+
+        // assert
+        assert!(!explanation.granted);
+        assert_eq!(explanation.statements.len(), 2);
+        assert_eq!(explanation.statements[1].statement_id, deny_id);
+        assert_eq!(
+            explanation.statements[1].database_operation,
+            DatabaseOperationType::Deny(vec!["READ".to_string()])
+        );
+    }
+}
@@ -0,0 +1,400 @@
+use std::{borrow::Cow, collections::HashMap};
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    access_matrix::AccessMatrix,
+    database::{AtomicPermission, Database, SetRegistry, StatementDependencies},
+    error::Result,
+    parse_set,
+    set_cache::SetCache,
+};
+
+// a change to a registry, kept in the registry history for point-in-time queries
+// a refresh keeps the whole registry it replaced, a membership delta only the change
+// so a stream of deltas does not copy the registry each time
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum RegistryChange {
+    // the registry a refresh replaced
+    Refresh(SetRegistry),
+    // a single membership added or removed, undone to recover the registry before it
+    // new_id is set when the id was registered by the delta
+    Membership {
+        key: String,
+        id: String,
+        insert: bool,
+        new_id: bool,
+        previous_etl_datetime: DateTime<Utc>,
+    },
+}
+
+impl RegistryChange {
+    // load time of the registry before the change
+    fn previous_etl_datetime(&self) -> DateTime<Utc> {
+        match self {
+            RegistryChange::Refresh(registry) => registry.etl_datetime,
+            RegistryChange::Membership {
+                previous_etl_datetime,
+                ..
+            } => *previous_etl_datetime,
+        }
+    }
+}
+
+// a point in the history of the database
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AsOf {
+    // statements received up to and including this time, evaluated at this time
+    Timestamp(DateTime<Utc>),
+    // statements up to and including this sequence, evaluated when the last of them was received
+    Sequence(u64),
+}
+
+impl Database {
+    // the database as it stood at a point in history, with the evaluation time to query it at
+    // statements are re-resolved against the registries valid at that time
+    // a key missing from those registries matches nothing rather than keeping the recorded set
+    pub fn as_of(&self, as_of: AsOf) -> Result<(Database, DateTime<Utc>)> {
+        let statements: Vec<_> = self
+            .ordered_statements()
+            .into_iter()
+            .filter(|statement| match as_of {
+                AsOf::Timestamp(at) => statement.metadata.command_received_datetime <= at,
+                AsOf::Sequence(sequence) => statement.sequence <= sequence,
+            })
+            .collect();
+
+        let at = match as_of {
+            AsOf::Timestamp(at) => at,
+            AsOf::Sequence(_) => statements
+                .last()
+                .map_or(DateTime::<Utc>::MIN_UTC, |statement| {
+                    statement.metadata.command_received_datetime
+                }),
+        };
+
+        let asset_registry = registry_as_of(&self.asset_registry, &self.asset_registry_history, at);
+        let user_registry = registry_as_of(&self.user_registry, &self.user_registry_history, at);
+        let (asset_registry, user_registry) = (asset_registry.as_ref(), user_registry.as_ref());
+
+        let (mut asset_cache, mut user_cache) = (SetCache::new(), SetCache::new());
+        let mut statement_log = HashMap::new();
+        for statement in statements {
+            let mut statement = statement.clone();
+            statement.resolve_against(
+                asset_registry,
+                &mut asset_cache,
+                user_registry,
+                &mut user_cache,
+            );
+            statement_log.insert(statement.statement_id.clone(), statement);
+        }
+
+        let mut database = Database {
+            asset_registry: asset_registry.clone(),
+            user_registry: user_registry.clone(),
+            asset_registry_history: Vec::new(),
+            user_registry_history: Vec::new(),
+            valid_permissions: self.valid_permissions.clone(),
+            permission_hierarchy: self.permission_hierarchy.clone(),
+            statement_log,
+            access_matrix: AccessMatrix::new(),
+            dependencies: StatementDependencies::default(),
+            log_sequence: 0,
+        };
+        database.rebuild_access_matrix();
+
+        Ok((database, at))
+    }
+
+    // every atomic permission in effect at a point in history, sorted
+    pub fn access_as_of(&self, as_of: AsOf) -> Result<Vec<AtomicPermission>> {
+        let (database, at) = self.as_of(as_of)?;
+        Ok(database.export_access(at))
+    }
+
+    pub fn check_as_of(
+        &self,
+        user_id: &str,
+        asset_id: &str,
+        permission: &str,
+        as_of: AsOf,
+    ) -> Result<bool> {
+        let (database, at) = self.as_of(as_of)?;
+        Ok(database.check_at(user_id, asset_id, permission, at))
+    }
+
+    // answers "who could READ on schema:tax at this point", sorted by user then asset
+    pub fn who_can_as_of(
+        &self,
+        permission: &str,
+        asset_set: &str,
+        as_of: AsOf,
+    ) -> Result<Vec<AtomicPermission>> {
+        let (database, at) = self.as_of(as_of)?;
+        let asset_ids = parse_set::parse(asset_set, &database.asset_registry)?;
+
+        Ok(database
+            .export_access(at)
+            .into_iter()
+            .filter(|atomic| {
+                atomic.permission == permission && asset_ids.contains(&atomic.asset_id)
+            })
+            .collect())
+    }
+}
+
+// the latest registry loaded at or before the given time
+// before the first recorded load the oldest registry available is used
+// a registry before a membership delta is rebuilt by undoing the deltas from the next refresh
+// or from the current registry
+fn registry_as_of<'a>(
+    current: &'a SetRegistry,
+    history: &'a [RegistryChange],
+    at: DateTime<Utc>,
+) -> Cow<'a, SetRegistry> {
+    if current.etl_datetime <= at {
+        return Cow::Borrowed(current);
+    }
+    let position = history
+        .iter()
+        .rposition(|change| change.previous_etl_datetime() <= at)
+        .unwrap_or(0);
+
+    let (end, base) = history[position..]
+        .iter()
+        .enumerate()
+        .find_map(|(offset, change)| match change {
+            RegistryChange::Refresh(registry) => Some((position + offset, registry)),
+            RegistryChange::Membership { .. } => None,
+        })
+        .unwrap_or((history.len(), current));
+    let deltas = &history[position..end];
+    if deltas.is_empty() {
+        return Cow::Borrowed(base);
+    }
+
+    let mut registry = base.clone();
+    for change in deltas.iter().rev() {
+        if let RegistryChange::Membership {
+            key,
+            id,
+            insert,
+            new_id,
+            previous_etl_datetime,
+        } = change
+        {
+            if *insert {
+                registry.delete(key, id);
+                if *new_id {
+                    registry.remove_id(id);
+                }
+            } else {
+                registry.insert(key.clone(), id.clone());
+            }
+            registry.etl_datetime = *previous_etl_datetime;
+        }
+    }
+    Cow::Owned(registry)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        registry_refresh::RegistryKind,
+        test_support::{datetime, mock_database, temp_path},
+    };
+
+    #[test]
+    fn test_as_of_sequence() {
+        // arrange
+        let mut database = mock_database();
+        database
+            .apply_command("GRANT READ ON schema:inventory TO department:tax")
+            .unwrap();
+        database
+            .apply_command("REVOKE READ ON schema:inventory FROM department:tax")
+            .unwrap();
+        let user_id = "laura.martinez@example.com";
+
+        // act
+        let before_revoke = database.access_as_of(AsOf::Sequence(1)).unwrap();
+        let after_revoke = database.access_as_of(AsOf::Sequence(2)).unwrap();
+
+        // assert
+        assert!(before_revoke.iter().any(|atomic| atomic.user_id == user_id));
+        assert!(after_revoke.is_empty());
+        assert!(database.access_as_of(AsOf::Sequence(0)).unwrap().is_empty());
+        assert!(database
+            .check_as_of(user_id, "inventory.items", "READ", AsOf::Sequence(1))
+            .unwrap());
+    }
+
+    #[test]
+    fn test_as_of_timestamp() {
+        // arrange
+        let mut database = mock_database();
+        let statement_id = database
+            .apply_command("GRANT READ ON schema:inventory TO department:tax UNTIL 2026-03-31")
+            .unwrap();
+        // pretend the grant was received at the start of the year
+        database
+            .statement_log
+            .get_mut(&statement_id)
+            .unwrap()
+            .metadata
+            .command_received_datetime = datetime("2026-01-01T00:00:00Z");
+
+        // act
+        let during = database
+            .who_can_as_of(
+                "READ",
+                "table:items",
+                AsOf::Timestamp(datetime("2026-03-31T12:00:00Z")),
+            )
+            .unwrap();
+        let expired = database
+            .who_can_as_of(
+                "READ",
+                "table:items",
+                AsOf::Timestamp(datetime("2026-04-01T00:00:00Z")),
+            )
+            .unwrap();
+        let before_received = database
+            .access_as_of(AsOf::Timestamp(datetime("2025-12-31T00:00:00Z")))
+            .unwrap();
+
+        // assert
+        assert!(!during.is_empty());
+        assert!(during
+            .iter()
+            .all(|atomic| atomic.asset_id == "inventory.items" && atomic.permission == "READ"));
+        assert!(expired.is_empty());
+        assert!(before_received.is_empty());
+    }
+
+    #[test]
+    fn test_registry_as_of_undoes_membership_deltas() {
+        // arrange - deltas either side of a refresh, each registry kept to compare against
+        let mut database = mock_database();
+        let mut expected = vec![database.user_registry.clone()];
+        database.update_membership(
+            RegistryKind::User,
+            "department:tax",
+            "new.starter@example.com",
+            true,
+            datetime("2030-01-01T00:00:00Z"),
+        );
+        expected.push(database.user_registry.clone());
+        let mut refreshed = database.user_registry.clone();
+        refreshed.delete("department:tax", "laura.martinez@example.com");
+        refreshed.etl_datetime = datetime("2030-02-01T00:00:00Z");
+        database.replace_user_registry(refreshed);
+        expected.push(database.user_registry.clone());
+        database.update_membership(
+            RegistryKind::User,
+            "department:tax",
+            "laura.martinez@example.com",
+            true,
+            datetime("2030-03-01T00:00:00Z"),
+        );
+        database.update_membership(
+            RegistryKind::User,
+            "department:tax",
+            "new.starter@example.com",
+            false,
+            datetime("2030-04-01T00:00:00Z"),
+        );
+        expected.push(database.user_registry.clone());
+
+        // act
+        let rebuilt: Vec<SetRegistry> = expected
+            .iter()
+            .map(|registry| {
+                registry_as_of(
+                    &database.user_registry,
+                    &database.user_registry_history,
+                    registry.etl_datetime,
+                )
+                .into_owned()
+            })
+            .collect();
+
+        // assert
+        assert_eq!(rebuilt, expected);
+        assert!(!rebuilt[0].contains_id("new.starter@example.com"));
+        assert!(rebuilt[1].contains_id("new.starter@example.com"));
+    }
+
+    #[test]
+    fn test_membership_deltas_do_not_copy_the_registry() {
+        // arrange
+        let mut database = mock_database();
+        let path = temp_path("many_deltas.json");
+        database.save_snapshot(&path).unwrap();
+        let size_before = std::fs::metadata(&path).unwrap().len();
+
+        // act
+        for i in 0..200 {
+            database.update_membership(
+                RegistryKind::User,
+                "department:tax",
+                "laura.martinez@example.com",
+                i % 2 == 1,
+                datetime("2030-01-01T00:00:00Z") + chrono::Duration::minutes(i),
+            );
+        }
+        database.save_snapshot(&path).unwrap();
+        let size_after = std::fs::metadata(&path).unwrap().len();
+
+        // assert - each delta records the change only, not the registry
+        assert_eq!(database.user_registry_history.len(), 200);
+        assert!((size_after - size_before) / 200 < 256);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_as_of_uses_registry_valid_at_that_time() {
+        // arrange - the user registry was reloaded after the grant, moving laura out of tax
+        let mut database = mock_database();
+        database
+            .apply_command("GRANT READ ON schema:inventory TO department:tax")
+            .unwrap();
+        let user_id = "laura.martinez@example.com";
+        let received = database.ordered_statements()[0]
+            .metadata
+            .command_received_datetime;
+
+        let mut reloaded = database.user_registry.clone();
+        reloaded.delete("department:tax", user_id);
+        reloaded.etl_datetime = received + chrono::Duration::days(1);
+        let previous = std::mem::replace(&mut database.user_registry, reloaded);
+        database
+            .user_registry_history
+            .push(RegistryChange::Refresh(previous));
+
+        // act
+        let before_reload = database
+            .check_as_of(
+                user_id,
+                "inventory.items",
+                "READ",
+                AsOf::Timestamp(received),
+            )
+            .unwrap();
+        let after_reload = database
+            .check_as_of(
+                user_id,
+                "inventory.items",
+                "READ",
+                AsOf::Timestamp(received + chrono::Duration::days(2)),
+            )
+            .unwrap();
+
+        // assert
+        assert!(before_reload);
+        assert!(!after_reload);
+    }
+}
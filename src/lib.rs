@@ -1,5 +1,18 @@
+pub mod access_matrix;
 pub mod config;
 pub mod database;
 pub mod database_pipeline;
+pub mod error;
+pub mod explain;
+pub mod history;
+pub mod parallel;
 pub mod parse_command;
 pub mod parse_set;
+pub mod permission_hierarchy;
+pub mod registry_refresh;
+pub mod set_cache;
+pub mod snapshot;
+pub mod suggest;
+#[cfg(test)]
+pub(crate) mod test_support;
+pub mod write_ahead_log;
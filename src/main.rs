@@ -1,24 +1,70 @@
 use rhubarb::database_pipeline::new_database_from_files;
+use rhubarb::parse_command::Submission;
+use rhubarb::registry_refresh::RegistryKind;
+use rhubarb::snapshot::new_database_from_snapshot;
+use rhubarb::write_ahead_log::WriteAheadLog;
 use std::io::{self, Write};
 use std::path::Path;
 
+const SNAPSHOT_PATH: &str = "rhubarb_snapshot.json";
+const WRITE_AHEAD_LOG_PATH: &str = "rhubarb_wal.log";
+
 fn main() {
-    let database = new_database_from_files(
-        Path::new("database_config.toml"),
-        Path::new("mock_data/assets.csv"),
-        Path::new("mock_data/employees.csv"),
-    )
-    .unwrap();
+    // resume from the last snapshot if there is one
+    let snapshot_path = Path::new(SNAPSHOT_PATH);
+    let loaded = if snapshot_path.exists() {
+        new_database_from_snapshot(snapshot_path)
+    } else {
+        new_database_from_files(
+            Path::new("database_config.toml"),
+            Path::new("mock_data/assets.csv"),
+            Path::new("mock_data/employees.csv"),
+        )
+    };
+
+    let mut database = match loaded {
+        Ok(database) => database,
+        Err(err) => {
+            eprintln!("{}", err);
+            std::process::exit(1);
+        }
+    };
+
+    // changes applied since the snapshot was taken are recovered from the log
+    let mut log = match WriteAheadLog::open(Path::new(WRITE_AHEAD_LOG_PATH))
+        .and_then(|log| log.replay(&mut database).map(|_| log))
+    {
+        Ok(log) => log,
+        Err(err) => {
+            eprintln!("{}", err);
+            std::process::exit(1);
+        }
+    };
 
     database.status_report();
     println!();
 
+    // statements are recorded against the OS user, ticket and justification are set with commands
+    let submitted_by = std::env::var("USER").unwrap_or_else(|_| "unknown".to_string());
+    let mut submission = Submission::new(&submitted_by);
+
     loop {
         print!("Enter command: ");
-        io::stdout().flush().unwrap();
+        if let Err(err) = io::stdout().flush() {
+            eprintln!("{}", err);
+            break;
+        }
 
         let mut command = String::new();
-        io::stdin().read_line(&mut command).unwrap();
+        match io::stdin().read_line(&mut command) {
+            // end of input
+            Ok(0) => break,
+            Ok(_) => {}
+            Err(err) => {
+                eprintln!("{}", err);
+                break;
+            }
+        }
 
         command = command.trim().to_string();
 
@@ -26,10 +72,67 @@ fn main() {
             break;
         }
 
-        let result = database.resolve_command(command.as_str());
+        // explain <user_id> <asset_id> <permission>
+        if let Some(args) = command.strip_prefix("explain ") {
+            let args: Vec<&str> = args.split_whitespace().collect();
+            match args.as_slice() {
+                [user_id, asset_id, permission] => {
+                    println!("{}", database.explain_access(user_id, asset_id, permission))
+                }
+                _ => println!("Usage: explain <user_id> <asset_id> <permission>"),
+            }
+            println!();
+            continue;
+        }
+
+        // refresh users <csv path> / refresh assets <csv path>
+        // the impact is shown and the refresh only applied once confirmed
+        if let Some(args) = command.strip_prefix("refresh ") {
+            let kind = match args.split_once(' ') {
+                Some(("users", path)) => Some((RegistryKind::User, path.trim())),
+                Some(("assets", path)) => Some((RegistryKind::Asset, path.trim())),
+                _ => None,
+            };
+            match kind {
+                Some((kind, path)) => match database.plan_registry_refresh(kind, Path::new(path)) {
+                    Ok(plan) => {
+                        println!("{}", plan);
+                        print!("Apply refresh? [y/N] ");
+                        let _ = io::stdout().flush();
+                        let mut answer = String::new();
+                        if io::stdin().read_line(&mut answer).is_ok() && answer.trim() == "y" {
+                            match database.commit_refresh_logged(plan, &mut log) {
+                                Ok(_) => println!("Refresh applied"),
+                                Err(err) => println!("{}", err),
+                            }
+                        }
+                    }
+                    Err(err) => println!("{}", err),
+                },
+                None => println!("Usage: refresh <users|assets> <csv path>"),
+            }
+            println!();
+            continue;
+        }
+
+        // ticket <reference> / justification <text> apply to the statements that follow
+        if let Some(ticket) = command.strip_prefix("ticket ") {
+            submission.ticket = Some(ticket.trim().to_string());
+            println!();
+            continue;
+        }
+        if let Some(justification) = command.strip_prefix("justification ") {
+            submission.justification = Some(justification.trim().to_string());
+            println!();
+            continue;
+        }
+
+        let result = database.apply_command_logged(command.as_str(), &submission, &mut log);
         match result {
-            Ok(parse_result) => {
-                println!("{}", parse_result);
+            Ok(statement_id) => {
+                if let Some(statement) = database.statement_log.get(&statement_id) {
+                    println!("{}", statement);
+                }
             }
             Err(err) => {
                 println!("{}", err);
@@ -37,4 +140,9 @@ fn main() {
         }
         println!();
     }
+
+    if let Err(err) = database.save_snapshot(snapshot_path) {
+        eprintln!("{}", err);
+        std::process::exit(1);
+    }
 }
@@ -0,0 +1,204 @@
+use rayon::prelude::*;
+
+use crate::{
+    database::{Database, Permission},
+    error::Result,
+    parse_command::{CommandParseResult, Submission},
+    parse_set::SetExpr,
+    set_cache::SetCache,
+};
+
+impl Database {
+    // resolves a batch of commands, e.g. a policy file, concurrently
+    // results are in input order and the first error in input order is returned
+    pub fn resolve_commands(
+        &self,
+        inputs: &[&str],
+        submission: &Submission,
+    ) -> Result<Vec<CommandParseResult>> {
+        let results: Vec<Result<CommandParseResult>> = inputs
+            .par_iter()
+            .map(|input| self.resolve_command_as(input, submission))
+            .collect();
+
+        results.into_iter().collect()
+    }
+
+    // applies a batch of commands in input order, rebuilding the access matrix once
+    // nothing is applied if any command fails, returns the statement ids
+    pub fn apply_commands(
+        &mut self,
+        inputs: &[&str],
+        submission: &Submission,
+    ) -> Result<Vec<String>> {
+        let statements = self.prepare_statements(inputs, submission)?;
+        Ok(self.insert_statements(statements))
+    }
+
+    // resolves the commands into the next statements, in input order, without storing them
+    pub fn prepare_statements(
+        &self,
+        inputs: &[&str],
+        submission: &Submission,
+    ) -> Result<Vec<Permission>> {
+        let first_sequence = self.last_sequence() + 1;
+        Ok(self
+            .resolve_commands(inputs, submission)?
+            .into_iter()
+            .zip(first_sequence..)
+            .map(|(command, sequence)| Permission::new(sequence, command))
+            .collect())
+    }
+
+    // stores prepared statements and rebuilds the access matrix once, returning the statement ids
+    pub fn insert_statements(&mut self, statements: Vec<Permission>) -> Vec<String> {
+        let statement_ids = statements
+            .into_iter()
+            .map(|statement| {
+                let statement_id = statement.statement_id.clone();
+                self.statement_log.insert(statement_id.clone(), statement);
+                statement_id
+            })
+            .collect();
+        self.rebuild_access_matrix();
+
+        statement_ids
+    }
+
+    // re-resolves every stored statement against the current registries concurrently
+    // the access matrix is left as is, see rebuild_access_matrix
+    pub fn resolve_statement_sets(&mut self) {
+        let (mut asset_cache, mut user_cache) = (SetCache::new(), SetCache::new());
+        self.resolve_statement_sets_with(&mut asset_cache, &mut user_cache);
+    }
+
+    // as resolve_statement_sets, memoising sub-expressions in the given caches
+    // each distinct sub-expression is evaluated once however many statements share it
+    pub fn resolve_statement_sets_with(
+        &mut self,
+        asset_cache: &mut SetCache,
+        user_cache: &mut SetCache,
+    ) {
+        let mut statements: Vec<&mut Permission> = self.statement_log.values_mut().collect();
+
+        let asset_exprs: Vec<&SetExpr> = statements
+            .iter()
+            .map(|statement| &statement.asset_set_expr)
+            .collect();
+        let asset_sets = asset_cache.evaluate_all(&asset_exprs, &self.asset_registry);
+        let user_exprs: Vec<&SetExpr> = statements
+            .iter()
+            .map(|statement| &statement.user_set_expr)
+            .collect();
+        let user_sets = user_cache.evaluate_all(&user_exprs, &self.user_registry);
+
+        // looking up names is the bulk of the work once the bitmaps are known
+        statements
+            .par_iter_mut()
+            .zip(asset_sets.par_iter().zip(&user_sets))
+            .for_each(|(statement, (asset_set, user_set))| {
+                statement.store_resolved(asset_set, user_set)
+            });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{error::RhubarbError, test_support::mock_database};
+
+    const POLICY: [&str; 4] = [
+        "GRANT READ ON schema:inventory TO (designation:partner OR designation:senior)",
+        "GRANT WRITE ON table:products TO department:tax AND (designation:senior OR designation:partner)",
+        "DENY READ ON table:items TO designation:senior",
+        "REVOKE READ ON schema:inventory FROM department:tax",
+    ];
+
+    #[test]
+    fn test_apply_commands_matches_applying_one_by_one() {
+        // arrange
+        let mut sequential = mock_database();
+        for input in POLICY {
+            sequential.apply_command(input).unwrap();
+        }
+        let mut batch = mock_database();
+
+        // act
+        let statement_ids = batch
+            .apply_commands(&POLICY, &Submission::new("carol"))
+            .unwrap();
+
+        // assert
+        assert_eq!(statement_ids.len(), POLICY.len());
+        let sequences: Vec<u64> = batch
+            .ordered_statements()
+            .iter()
+            .map(|statement| statement.sequence)
+            .collect();
+        assert_eq!(sequences, vec![1, 2, 3, 4]);
+        assert_eq!(
+            batch.statement_log[&statement_ids[2]].command_raw,
+            POLICY[2]
+        );
+        let now = chrono::Utc::now();
+        assert_eq!(batch.export_access(now), sequential.export_access(now));
+    }
+
+    #[test]
+    fn test_apply_commands_applies_nothing_on_error() {
+        // arrange
+        let mut database = mock_database();
+
+        // act
+        let result = database.apply_commands(
+            &[
+                POLICY[0],
+                "GRANT READ ON schema:nope TO department:tax",
+                POLICY[1],
+            ],
+            &Submission::new("carol"),
+        );
+
+        // assert
+        assert!(matches!(result, Err(RhubarbError::UnknownKeys(_))));
+        assert!(database.statement_log.is_empty());
+    }
+
+    #[test]
+    fn test_resolve_statement_sets_matches_sequential() {
+        // arrange
+        let mut database = mock_database();
+        database
+            .apply_commands(&POLICY, &Submission::new("carol"))
+            .unwrap();
+        let mut registry = database.user_registry.clone();
+        registry.delete("department:tax", "laura.martinez@example.com");
+        database.user_registry = registry;
+
+        let mut expected = database.statement_log.clone();
+        let (mut asset_cache, mut user_cache) = (SetCache::new(), SetCache::new());
+        for statement in expected.values_mut() {
+            statement.resolve_against(
+                &database.asset_registry,
+                &mut asset_cache,
+                &database.user_registry,
+                &mut user_cache,
+            );
+        }
+
+        // act
+        database.resolve_statement_sets();
+
+        // assert
+        for (statement_id, statement) in &expected {
+            assert_eq!(
+                database.statement_log[statement_id].user_set_affected,
+                statement.user_set_affected
+            );
+            assert_eq!(
+                database.statement_log[statement_id].asset_set_affected,
+                statement.asset_set_affected
+            );
+        }
+    }
+}
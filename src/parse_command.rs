@@ -1,22 +1,40 @@
 use crate::{
-    database::Database,
+    database::{Database, Validity},
+    error::{expect, ParseError, ParseResult, Result, RhubarbError},
     parse_set::{self, SetExpr},
 };
+use chrono::{DateTime, NaiveDate, Utc};
 use nom::{
-    bytes::complete::tag,
-    character::complete::{alpha1, multispace1},
-    Finish, IResult,
+    branch::alt,
+    bytes::complete::{tag, take_till1},
+    character::complete::{alpha1, char, multispace0, multispace1},
+    combinator::{cut, map, map_opt, opt},
+    multi::separated_list1,
+    sequence::{delimited, preceded},
 };
+use serde::{Deserialize, Serialize};
 use std::{collections::HashSet, fmt};
 
 #[derive(Debug, Clone)]
 pub struct CommandParseResult {
     //command_origin: String,
-    command_raw: String,
-    database_operation: DatabaseOperationType,
-    asset_set_affected: HashSet<String>, // set of asset ids
-    user_set_affected: HashSet<String>,  // set of user ids
-    metadata: CommandParseResultMetadata,
+    pub command_raw: String,
+    pub database_operation: DatabaseOperationType,
+    pub asset_set_expr: SetExpr,
+    pub user_set_expr: SetExpr,
+    pub asset_set_affected: HashSet<String>, // set of asset ids
+    pub user_set_affected: HashSet<String>,  // set of user ids
+    pub validity: Validity,
+    pub metadata: CommandParseResultMetadata,
+}
+
+// a command as parsed, before its sets are resolved against the registries
+#[derive(Debug, Clone, PartialEq)]
+pub struct ParsedCommand {
+    pub database_operation: DatabaseOperationType,
+    pub asset_set_expr: SetExpr,
+    pub user_set_expr: SetExpr,
+    pub validity: Validity,
 }
 
 impl fmt::Display for CommandParseResult {
@@ -25,116 +43,517 @@ impl fmt::Display for CommandParseResult {
         write!(f, "database_operation: {:?},\n\n", self.database_operation)?;
         write!(f, "asset_set_affected: {:?},\n\n", self.asset_set_affected)?;
         write!(f, "user_set_affected: {:?},\n\n", self.user_set_affected)?;
+        write!(f, "validity: {},\n\n", self.validity)?;
+        write!(f, "metadata: {},\n\n", self.metadata)?;
         write!(f, "}}")
     }
 }
 
-#[derive(Debug, Clone)]
+// who submitted a command and why, supplied alongside the command text
+#[derive(Debug, Clone, PartialEq)]
+pub struct Submission {
+    pub submitted_by: String,
+    // reference into an external ticketing system e.g. SEC-1234
+    pub ticket: Option<String>,
+    pub justification: Option<String>,
+}
+
+impl Submission {
+    pub fn new(submitted_by: &str) -> Submission {
+        Submission {
+            submitted_by: submitted_by.to_string(),
+            ticket: None,
+            justification: None,
+        }
+    }
+
+    pub fn with_ticket(mut self, ticket: &str) -> Submission {
+        self.ticket = Some(ticket.to_string());
+        self
+    }
+
+    pub fn with_justification(mut self, justification: &str) -> Submission {
+        self.justification = Some(justification.to_string());
+        self
+    }
+}
+
+// audit trail of a statement, recorded when the command is resolved
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct CommandParseResultMetadata {
-    //command_received_datetime: DateTime<Utc>,
-    //asset_registry_etl_datetime: DateTime<Utc>,
-    //user_registry_etl_datetime: DateTime<Utc>,
+    pub submitted_by: String,
+    pub command_received_datetime: DateTime<Utc>,
+    pub ticket: Option<String>,
+    pub justification: Option<String>,
+    // registry snapshots the sets were resolved against when the command was received
+    pub asset_registry_etl_datetime: DateTime<Utc>,
+    pub user_registry_etl_datetime: DateTime<Utc>,
+    // registry snapshots the stored sets were last re-resolved against, None until a refresh
+    pub last_resolved_asset_etl_datetime: Option<DateTime<Utc>>,
+    pub last_resolved_user_etl_datetime: Option<DateTime<Utc>>,
 }
+
 impl CommandParseResultMetadata {
-    pub fn new() -> CommandParseResultMetadata {
-        CommandParseResultMetadata {}
+    pub fn new(
+        submission: &Submission,
+        asset_registry_etl_datetime: DateTime<Utc>,
+        user_registry_etl_datetime: DateTime<Utc>,
+    ) -> CommandParseResultMetadata {
+        CommandParseResultMetadata {
+            submitted_by: submission.submitted_by.clone(),
+            command_received_datetime: Utc::now(),
+            ticket: submission.ticket.clone(),
+            justification: submission.justification.clone(),
+            asset_registry_etl_datetime,
+            user_registry_etl_datetime,
+            last_resolved_asset_etl_datetime: None,
+            last_resolved_user_etl_datetime: None,
+        }
     }
 }
 
-#[derive(Debug, Clone)]
+impl fmt::Display for CommandParseResultMetadata {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "submitted by {} at {}",
+            self.submitted_by,
+            self.command_received_datetime.to_rfc3339()
+        )?;
+        if let Some(ticket) = &self.ticket {
+            write!(f, ", ticket {}", ticket)?;
+        }
+        if let Some(justification) = &self.justification {
+            write!(f, ", justification: {}", justification)?;
+        }
+        Ok(())
+    }
+}
+
+// each operation carries one or more permissions e.g. GRANT READ, WRITE ON
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub enum DatabaseOperationType {
-    Grant(String),
+    Grant(Vec<String>),
+    Revoke(Vec<String>),
+    Deny(Vec<String>),
+}
+
+impl DatabaseOperationType {
+    pub fn permissions(&self) -> &[String] {
+        match self {
+            DatabaseOperationType::Grant(permissions) => permissions,
+            DatabaseOperationType::Revoke(permissions) => permissions,
+            DatabaseOperationType::Deny(permissions) => permissions,
+        }
+    }
+
+    fn with_permissions(&self, permissions: Vec<String>) -> DatabaseOperationType {
+        match self {
+            DatabaseOperationType::Grant(_) => DatabaseOperationType::Grant(permissions),
+            DatabaseOperationType::Revoke(_) => DatabaseOperationType::Revoke(permissions),
+            DatabaseOperationType::Deny(_) => DatabaseOperationType::Deny(permissions),
+        }
+    }
 }
 
 impl Database {
-    pub fn resolve_command(&self, input: &str) -> anyhow::Result<CommandParseResult> {
+    pub fn resolve_command(&self, input: &str) -> Result<CommandParseResult> {
+        self.resolve_command_as(input, &Submission::new("unknown"))
+    }
+
+    pub fn resolve_command_as(
+        &self,
+        input: &str,
+        submission: &Submission,
+    ) -> Result<CommandParseResult> {
         let command_raw = input.to_string();
 
-        let (leftover, (database_operation, asset_set_expr, user_set_expr)) =
-            match parse_command_to_expr(input).finish() {
-                Ok(x) => x,
-                Err(err) => return Err(anyhow::anyhow!(err.to_string())),
-            };
-
-        // check leftover - means parsing failed in some unexpected way
-        if !leftover.is_empty() {
-            return Err(anyhow::anyhow!(
-                "Unexpected input was not parsed correctly: {}",
-                leftover
-            ));
-        }
-
-        // check permission validity
-        match database_operation.clone() {
-            DatabaseOperationType::Grant(permission) => {
-                if !self.valid_permissions.contains(&permission) {
-                    return Err(anyhow::anyhow!("Invalid permission type"));
-                }
+        let ParsedCommand {
+            database_operation,
+            asset_set_expr,
+            user_set_expr,
+            validity,
+        } = parse_command_to_expr(input)?;
+
+        if let (Some(valid_from), Some(valid_until)) = (validity.valid_from, validity.valid_until) {
+            if valid_from >= valid_until {
+                return Err(RhubarbError::EmptyValidityWindow {
+                    valid_from,
+                    valid_until,
+                });
             }
         }
 
-        // resolve sets
-        let asset_set_affected = parse_set::resolve_set(asset_set_expr, &self.asset_registry.data)?;
-        let user_set_affected = parse_set::resolve_set(user_set_expr, &self.user_registry.data)?;
+        // expand ALL and check permission validity
+        let permissions = self.expand_permissions(database_operation.permissions())?;
+        let database_operation = database_operation.with_permissions(permissions);
+
+        // check keys on both sides so every unknown key is reported at once
+        let mut unknown_keys = parse_set::unknown_keys(&asset_set_expr, &self.asset_registry);
+        unknown_keys.extend(parse_set::unknown_keys(&user_set_expr, &self.user_registry));
+        if !unknown_keys.is_empty() {
+            return Err(RhubarbError::UnknownKeys(unknown_keys));
+        }
+
+        // resolve sets, the keys are known to exist
+        let asset_set_affected =
+            parse_set::evaluate_adaptive(&asset_set_expr, &self.asset_registry).to_names();
+        let user_set_affected =
+            parse_set::evaluate_adaptive(&user_set_expr, &self.user_registry).to_names();
 
         let result = CommandParseResult {
             command_raw,
             database_operation,
+            asset_set_expr,
+            user_set_expr,
             asset_set_affected,
             user_set_affected,
-            metadata: CommandParseResultMetadata::new(),
+            validity,
+            metadata: CommandParseResultMetadata::new(
+                submission,
+                self.asset_registry.etl_datetime,
+                self.user_registry.etl_datetime,
+            ),
         };
 
-        anyhow::Ok(result)
+        Ok(result)
     }
-}
 
-pub fn parse_command_to_expr(
-    input: &str,
-) -> IResult<&str, (DatabaseOperationType, SetExpr, SetExpr)> {
-    // GRANT READ ON
-    let (input, database_operation) = parse_operation(input)?;
+    // every permission must be valid or ALL, which expands to every valid permission
+    fn expand_permissions(&self, permissions: &[String]) -> Result<Vec<String>> {
+        if let Some(invalid) = permissions
+            .iter()
+            .find(|permission| *permission != "ALL" && !self.valid_permissions.contains(permission))
+        {
+            return Err(RhubarbError::InvalidPermission(invalid.clone()));
+        }
 
-    // Split the remaining input on " TO "
-    let parts: Vec<&str> = input.splitn(2, " TO ").collect();
+        if permissions.iter().any(|permission| permission == "ALL") {
+            return Ok(self.valid_permissions.clone());
+        }
 
-    if parts.len() != 2 {
-        return Err(nom::Err::Error(nom::error::Error::new(input, nom::error::ErrorKind::Count)));
+        let mut expanded: Vec<String> = Vec::new();
+        for permission in permissions {
+            if !expanded.contains(permission) {
+                expanded.push(permission.clone());
+            }
+        }
+
+        Ok(expanded)
     }
+}
+
+// parses a whole command e.g. GRANT READ ON schema:tax TO department:tax
+pub fn parse_command_to_expr(input: &str) -> std::result::Result<ParsedCommand, ParseError> {
+    match parse_command(input) {
+        Ok((_, parsed)) => Ok(parsed),
+        Err(err) => Err(ParseError::from_nom(input, err)),
+    }
+}
+
+fn parse_command(input: &str) -> ParseResult<'_, ParsedCommand> {
+    // GRANT READ ON / REVOKE READ ON / DENY READ ON
+    let (input, database_operation) = parse_operation(input)?;
 
     // first set - the asset set e.g. (schema:tax EXCEPT table:sensitive_audit)
-    let (leftover_1, asset_set_expr) = parse_set::parse_expr(parts[0])?;
+    let (input, asset_set_expr) = parse_set::parse_expr(input)?;
+
+    // TO (GRANT, DENY) or FROM (REVOKE)
+    let (input, _) = match database_operation {
+        DatabaseOperationType::Grant(_) | DatabaseOperationType::Deny(_) => {
+            parse_separator(&["AND", "EXCEPT", "OR", "TO"], "TO")(input)?
+        }
+        DatabaseOperationType::Revoke(_) => {
+            parse_separator(&["AND", "EXCEPT", "OR", "FROM"], "FROM")(input)?
+        }
+    };
 
     // second set - the user set e.g. (department:tax AND (designation:partner OR designation:senior))
-    let (leftover_2, user_set_expr) = parse_set::parse_expr(parts[1])?;
+    let (input, user_set_expr) = parse_set::parse_expr(input)?;
 
-    // Return the first leftover that is not empty - otherwise it's impossible to combine and return a string ref
-    // but this is going to screw up the result if both fail
+    // only GRANT statements take a validity window e.g. FROM 2024-01-01 UNTIL 2024-03-31
+    let (input, validity) = match database_operation {
+        DatabaseOperationType::Grant(_) => parse_validity(input)?,
+        _ => (input, Validity::default()),
+    };
 
-    // WIP
+    let expected: &'static [&'static str] = match database_operation {
+        DatabaseOperationType::Grant(_) if validity.valid_until.is_some() => &["end of input"],
+        DatabaseOperationType::Grant(_) if validity.valid_from.is_some() => {
+            &["UNTIL", "end of input"]
+        }
+        DatabaseOperationType::Grant(_) => {
+            &["AND", "EXCEPT", "OR", "FROM", "UNTIL", "end of input"]
+        }
+        _ => &["AND", "EXCEPT", "OR", "end of input"],
+    };
+    let (input, _) = parse_set::parse_end(expected)(input)?;
 
-    let leftover = if !leftover_1.trim().is_empty() {
-        leftover_1
-    } else if !leftover_2.trim().is_empty() {
-        leftover_2
-    } else {
-        ""
+    let parsed = ParsedCommand {
+        database_operation,
+        asset_set_expr,
+        user_set_expr,
+        validity,
     };
 
-    Ok((
-        leftover,
-        (database_operation, asset_set_expr, user_set_expr),
-    ))
+    Ok((input, parsed))
 }
 
-// Only supports GRANT now
-pub fn parse_operation(input: &str) -> IResult<&str, DatabaseOperationType> {
-    let (input, _) = tag("GRANT")(input)?;
+// optional FROM and UNTIL clauses, in that order
+fn parse_validity(input: &str) -> ParseResult<'_, Validity> {
+    let (input, valid_from) = opt(preceded(
+        delimited(multispace1, tag("FROM"), multispace1),
+        cut(parse_timestamp(false)),
+    ))(input)?;
+    let (input, valid_until) = opt(preceded(
+        delimited(multispace1, tag("UNTIL"), multispace1),
+        cut(parse_timestamp(true)),
+    ))(input)?;
+
+    let validity = Validity {
+        valid_from,
+        valid_until,
+    };
+
+    Ok((input, validity))
+}
+
+// YYYY-MM-DD or RFC 3339 e.g. 2024-03-31T17:00:00Z
+// a bare date is read as midnight UTC, UNTIL a bare date includes the whole of that day
+fn parse_timestamp<'a>(until: bool) -> impl FnMut(&'a str) -> ParseResult<'a, DateTime<Utc>> {
+    let token = take_till1(|c: char| c.is_whitespace());
+
+    expect(
+        &["date"],
+        map_opt(token, move |s: &str| {
+            if let Ok(datetime) = DateTime::parse_from_rfc3339(s) {
+                return Some(datetime.with_timezone(&Utc));
+            }
+
+            let date = NaiveDate::parse_from_str(s, "%Y-%m-%d").ok()?;
+            let date = if until { date.succ_opt()? } else { date };
+            Some(date.and_hms_opt(0, 0, 0)?.and_utc())
+        }),
+    )
+}
+
+fn parse_separator<'a>(
+    expected: &'static [&'static str],
+    keyword: &'static str,
+) -> impl FnMut(&'a str) -> ParseResult<'a, &'a str> {
+    expect(expected, delimited(multispace1, tag(keyword), multispace1))
+}
+
+// Supports GRANT, REVOKE and DENY
+pub fn parse_operation(input: &str) -> ParseResult<'_, DatabaseOperationType> {
+    let (input, keyword) = expect(
+        &["GRANT", "REVOKE", "DENY"],
+        alt((tag("GRANT"), tag("REVOKE"), tag("DENY"))),
+    )(input)?;
     let (input, _) = multispace1(input)?;
-    let (input, operation) = alpha1(input)?;
+    let (input, permissions) = parse_permissions(input)?;
 
     // throwaway ON
-    let (input, _) = tag(" ON ")(input)?;
+    let (input, _) = expect(&["ON"], delimited(multispace1, tag("ON"), multispace1))(input)?;
+
+    let database_operation = match keyword {
+        "GRANT" => DatabaseOperationType::Grant(permissions),
+        "REVOKE" => DatabaseOperationType::Revoke(permissions),
+        _ => DatabaseOperationType::Deny(permissions),
+    };
+
+    Ok((input, database_operation))
+}
+
+// comma separated permissions e.g. READ, WRITE or ALL
+fn parse_permissions(input: &str) -> ParseResult<'_, Vec<String>> {
+    let separator = delimited(multispace0, char(','), multispace0);
+    let permission = map(expect(&["permission"], alpha1), |s: &str| s.to_string());
+
+    separated_list1(separator, permission)(input)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_operation_grant() {
+        let (leftover, operation) = parse_operation("GRANT READ ON schema:tax").unwrap();
+
+        assert_eq!(
+            operation,
+            DatabaseOperationType::Grant(vec!["READ".to_string()])
+        );
+        assert_eq!(leftover, "schema:tax");
+    }
+
+    #[test]
+    fn test_parse_command_revoke() {
+        // act
+        let parsed = parse_command_to_expr("REVOKE WRITE ON schema:tax FROM (A OR B)").unwrap();
+
+        // assert
+        assert_eq!(
+            parsed.database_operation,
+            DatabaseOperationType::Revoke(vec!["WRITE".to_string()])
+        );
+        assert_eq!(
+            parsed.asset_set_expr,
+            SetExpr::Set("schema:tax".to_string())
+        );
+        assert_eq!(
+            parsed.user_set_expr,
+            SetExpr::Union(
+                Box::new(SetExpr::Set("A".to_string())),
+                Box::new(SetExpr::Set("B".to_string()))
+            )
+        );
+    }
+
+    #[test]
+    fn test_parse_operation_deny() {
+        let (leftover, operation) = parse_operation("DENY READ ON schema:tax").unwrap();
+
+        assert_eq!(
+            operation,
+            DatabaseOperationType::Deny(vec!["READ".to_string()])
+        );
+        assert_eq!(leftover, "schema:tax");
+    }
+
+    #[test]
+    fn test_parse_operation_multiple_permissions() {
+        let (leftover, operation) = parse_operation("GRANT READ, WRITE ,ADMIN ON A").unwrap();
 
-    Ok((input, DatabaseOperationType::Grant(operation.to_string())))
-}
\ No newline at end of file
+        assert_eq!(
+            operation,
+            DatabaseOperationType::Grant(vec![
+                "READ".to_string(),
+                "WRITE".to_string(),
+                "ADMIN".to_string()
+            ])
+        );
+        assert_eq!(leftover, "A");
+
+        assert!(parse_operation("GRANT READ, ON A").is_err());
+    }
+
+    #[test]
+    fn test_parse_command_revoke_requires_from() {
+        let err = parse_command_to_expr("REVOKE WRITE ON schema:tax TO A").unwrap_err();
+
+        assert_eq!(err.offset, 27);
+        assert_eq!(err.found, "'TO'");
+        assert!(err.expected.contains(&"FROM".to_string()));
+    }
+
+    #[test]
+    fn test_parse_command_unparenthesized() {
+        let parsed = parse_command_to_expr(
+            "GRANT READ ON schema:tax EXCEPT table:audit TO department:tax AND designation:partner OR designation:senior",
+        )
+        .unwrap();
+
+        assert_eq!(
+            parsed.asset_set_expr.to_string(),
+            "(schema:tax EXCEPT table:audit)"
+        );
+        assert_eq!(
+            parsed.user_set_expr.to_string(),
+            "((department:tax AND designation:partner) OR designation:senior)"
+        );
+    }
+
+    #[test]
+    fn test_parse_command_error_caret() {
+        let err = parse_command_to_expr("GRANT READ ON (schema:tax OR) TO A").unwrap_err();
+
+        assert_eq!(err.line, 1);
+        assert_eq!(err.column, 29);
+        assert_eq!(err.found, "')'");
+        assert_eq!(
+            err.to_string(),
+            "Syntax error at line 1, column 29: expected one of NOT, (, set key, found ')'\n\
+             GRANT READ ON (schema:tax OR) TO A\n\
+             \x20                           ^"
+        );
+    }
+
+    #[test]
+    fn test_parse_command_malformed_input_is_err() {
+        for input in [
+            "",
+            "GRANT",
+            "GRANT READ",
+            "GRANT READ ON",
+            "GRANT READ ON A",
+            "GRANT READ ON A TO",
+            "GRANT READ ON (A TO B",
+            "REVOKE READ ON A TO B",
+            "GRANT READ ON A TO B)",
+            "GRANT ü ON A TO B",
+            "GRANT READ ON A TO (B OR",
+            "GRANT READ ON A TO B UNTIL",
+            "GRANT READ ON A TO B UNTIL 2024-02-30",
+            "GRANT READ ON A TO B UNTIL 2024-03-31 FROM 2024-01-01",
+            "DENY READ ON A TO B UNTIL 2024-03-31",
+        ] {
+            assert!(
+                parse_command_to_expr(input).is_err(),
+                "Should return a parse error for '{}'",
+                input
+            );
+        }
+    }
+
+    #[test]
+    fn test_parse_command_trailing_input() {
+        let err = parse_command_to_expr("GRANT READ ON A TO B C").unwrap_err();
+
+        assert_eq!(err.offset, 21);
+        assert_eq!(
+            err.expected,
+            vec!["AND", "EXCEPT", "OR", "FROM", "UNTIL", "end of input"]
+        );
+    }
+
+    #[test]
+    fn test_parse_command_validity_window() {
+        // act
+        let parsed =
+            parse_command_to_expr("GRANT READ ON A TO B OR C FROM 2024-01-01 UNTIL 2024-03-31")
+                .unwrap();
+
+        // assert - a bare UNTIL date includes the whole day
+        assert_eq!(parsed.user_set_expr.to_string(), "(B OR C)");
+        assert_eq!(
+            parsed.validity,
+            Validity {
+                valid_from: Some("2024-01-01T00:00:00Z".parse().unwrap()),
+                valid_until: Some("2024-04-01T00:00:00Z".parse().unwrap()),
+            }
+        );
+    }
+
+    #[test]
+    fn test_parse_command_validity_rfc3339() {
+        let parsed =
+            parse_command_to_expr("GRANT READ ON A TO B UNTIL 2024-03-31T17:00:00+08:00").unwrap();
+
+        assert_eq!(parsed.validity.valid_from, None);
+        assert_eq!(
+            parsed.validity.valid_until,
+            Some("2024-03-31T09:00:00Z".parse().unwrap())
+        );
+    }
+
+    #[test]
+    fn test_parse_command_bad_date() {
+        let err = parse_command_to_expr("GRANT READ ON A TO B FROM 2024-13-01").unwrap_err();
+
+        assert_eq!(err.offset, 26);
+        assert_eq!(err.expected, vec!["date"]);
+        assert_eq!(err.found, "'2024-13-01'");
+    }
+}
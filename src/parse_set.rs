@@ -1,149 +1,594 @@
 use nom::{
     branch::alt,
-    bytes::complete::{tag, take_till},
-    character::complete::{char, multispace0},
-    combinator::map,
-    sequence::{delimited, preceded, tuple},
-    Finish, IResult,
+    bytes::complete::{tag, take_till1},
+    character::complete::{multispace0, multispace1},
+    combinator::{all_consuming, eof, map, peek, verify},
+    sequence::{preceded, terminated},
+};
+use roaring::RoaringBitmap;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::{
+    borrow::Cow,
+    collections::HashSet,
+    fmt,
+    hash::{DefaultHasher, Hash, Hasher},
+};
+
+use crate::{
+    database::SetRegistry,
+    error::{expect, ParseError, ParseResult, Result, RhubarbError, SyntaxError, UnknownKey},
+    suggest,
 };
-use std::collections::{HashMap, HashSet};
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum SetExpr {
     Set(String),
     Union(Box<SetExpr>, Box<SetExpr>),        // OR
     Intersection(Box<SetExpr>, Box<SetExpr>), // AND
     Except(Box<SetExpr>, Box<SetExpr>),       // EXCEPT which is shorthand for A n B'
+    Not(Box<SetExpr>),                        // NOT which is shorthand for ALL EXCEPT A
+    Universe,                                 // ALL or * which is every id in the registry
 }
 
-// parses set lang only
-// expecting this type of format (A OR (B AND C))
-pub fn parse(
-    input: &str,
-    set_map: &HashMap<String, HashSet<String>>,
-) -> anyhow::Result<HashSet<String>> {
-    // need to transform the error to an owned error to prevent lifetime issues
-    let (leftover, parsed_expr) = match parse_expr(input).finish() {
-        Ok(x) => Ok(x),
-        Err(err) => Err(err.to_string()),
-    }
-    .unwrap();
-
-    // analyse leftover - means parsing failed in some unexpected way
-    if !leftover.is_empty() {
-        return Err(anyhow::anyhow!(
-            "Unexpected input was not parsed correctly: {}",
-            leftover
-        ));
+impl fmt::Display for SetExpr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SetExpr::Set(key) => write!(f, "{}", key),
+            SetExpr::Union(left, right) => write!(f, "({} OR {})", left, right),
+            SetExpr::Intersection(left, right) => write!(f, "({} AND {})", left, right),
+            SetExpr::Except(left, right) => write!(f, "({} EXCEPT {})", left, right),
+            SetExpr::Not(inner) => write!(f, "NOT {}", inner),
+            SetExpr::Universe => write!(f, "ALL"),
+        }
     }
+}
+
+// stored as its text, a nested JSON form would hit the nesting limit of serde_json
+// well before MAX_NESTING_DEPTH, the parser checks the depth when it is loaded
+impl Serialize for SetExpr {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
 
-    let result = resolve_set(parsed_expr, set_map)?;
+impl<'de> Deserialize<'de> for SetExpr {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        let text = String::deserialize(deserializer)?;
+        parse_set_expr(&text).map_err(serde::de::Error::custom)
+    }
+}
+
+impl SetExpr {
+    // every key referenced by the expression, in order of first appearance
+    pub fn keys(&self) -> Vec<&str> {
+        let mut keys = Vec::new();
+        self.collect_keys(&mut keys);
+        keys
+    }
+
+    // ALL and NOT resolve against the ids of the registry as well as its keys
+    pub fn depends_on_ids(&self) -> bool {
+        match self {
+            SetExpr::Set(_) => false,
+            SetExpr::Union(left, right)
+            | SetExpr::Intersection(left, right)
+            | SetExpr::Except(left, right) => left.depends_on_ids() || right.depends_on_ids(),
+            SetExpr::Not(_) | SetExpr::Universe => true,
+        }
+    }
+
+    // a single key or ALL, evaluated by borrowing a registry bitmap
+    pub fn is_leaf(&self) -> bool {
+        matches!(self, SetExpr::Set(_) | SetExpr::Universe)
+    }
+
+    // operators on the longest path from the root, walked with an explicit stack
+    // so expressions built outside the parser can be checked against MAX_NESTING_DEPTH
+    pub fn depth(&self) -> usize {
+        let mut deepest = 0;
+        let mut pending = vec![(self, 0)];
+        while let Some((expr, depth)) = pending.pop() {
+            deepest = deepest.max(depth);
+            match expr {
+                SetExpr::Union(left, right)
+                | SetExpr::Intersection(left, right)
+                | SetExpr::Except(left, right) => {
+                    pending.push((left, depth + 1));
+                    pending.push((right, depth + 1));
+                }
+                SetExpr::Not(inner) => pending.push((inner, depth + 1)),
+                SetExpr::Set(_) | SetExpr::Universe => {}
+            }
+        }
+        deepest
+    }
+
+    // equal for expressions that only differ in the order of OR and AND operands
+    // e.g. (A OR B) and (B OR A), used to memoise sub-expressions shared between statements
+    pub fn canonical_hash(&self) -> u64 {
+        assert_nesting_depth(self);
+        self.hash_canonical()
+    }
+
+    fn hash_canonical(&self) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        match self {
+            SetExpr::Set(key) => (0u8, key).hash(&mut hasher),
+            SetExpr::Union(left, right) => (
+                1u8,
+                unordered(left.hash_canonical(), right.hash_canonical()),
+            )
+                .hash(&mut hasher),
+            SetExpr::Intersection(left, right) => (
+                2u8,
+                unordered(left.hash_canonical(), right.hash_canonical()),
+            )
+                .hash(&mut hasher),
+            SetExpr::Except(left, right) => {
+                (3u8, left.hash_canonical(), right.hash_canonical()).hash(&mut hasher)
+            }
+            SetExpr::Not(inner) => (4u8, inner.hash_canonical()).hash(&mut hasher),
+            SetExpr::Universe => 5u8.hash(&mut hasher),
+        }
+        hasher.finish()
+    }
+
+    // equality up to the order of OR and AND operands, as for canonical_hash
+    pub fn canonical_eq(&self, other: &SetExpr) -> bool {
+        match (self, other) {
+            (SetExpr::Union(a, b), SetExpr::Union(c, d))
+            | (SetExpr::Intersection(a, b), SetExpr::Intersection(c, d)) => {
+                (a.canonical_eq(c) && b.canonical_eq(d)) || (a.canonical_eq(d) && b.canonical_eq(c))
+            }
+            (SetExpr::Except(a, b), SetExpr::Except(c, d)) => {
+                a.canonical_eq(c) && b.canonical_eq(d)
+            }
+            (SetExpr::Not(a), SetExpr::Not(b)) => a.canonical_eq(b),
+            (SetExpr::Set(a), SetExpr::Set(b)) => a == b,
+            (SetExpr::Universe, SetExpr::Universe) => true,
+            _ => false,
+        }
+    }
+
+    fn collect_keys<'a>(&'a self, keys: &mut Vec<&'a str>) {
+        match self {
+            SetExpr::Set(key) => {
+                if !keys.contains(&key.as_str()) {
+                    keys.push(key);
+                }
+            }
+            SetExpr::Union(left, right)
+            | SetExpr::Intersection(left, right)
+            | SetExpr::Except(left, right) => {
+                left.collect_keys(keys);
+                right.collect_keys(keys);
+            }
+            SetExpr::Not(inner) => inner.collect_keys(keys),
+            SetExpr::Universe => {}
+        }
+    }
+}
+
+// the recursive walks below are only bounded for expressions within MAX_NESTING_DEPTH
+// the parser never builds deeper ones, this catches any built by hand
+fn assert_nesting_depth(parsed_expression: &SetExpr) {
+    assert!(
+        parsed_expression.depth() <= MAX_NESTING_DEPTH,
+        "set expression nests deeper than {} levels",
+        MAX_NESTING_DEPTH
+    );
+}
+
+fn unordered(a: u64, b: u64) -> (u64, u64) {
+    (a.min(b), a.max(b))
+}
+
+// parses set lang only
+// expecting this type of format A OR (B AND C), where AND / EXCEPT bind tighter than OR
+pub fn parse(input: &str, registry: &SetRegistry) -> Result<HashSet<String>> {
+    let parsed_expr = parse_set_expr(input)?;
+
+    let result = resolve_set(parsed_expr, registry)?;
 
     Ok(result)
 }
 
-fn parse_set(input: &str) -> IResult<&str, SetExpr> {
-    let whitespace_or_parentheses = |c: char| c.is_whitespace() || c == '(' || c == ')';
-    let parser = take_till(whitespace_or_parentheses);
-    // nom map applies the parser to input, then applies the closure to the result
-    map(parser, |s: &str| SetExpr::Set(s.to_string()))(input)
+// parses the whole input as a single set expression
+pub fn parse_set_expr(input: &str) -> std::result::Result<SetExpr, ParseError> {
+    let parser = terminated(
+        parse_expr,
+        parse_end(&["AND", "EXCEPT", "OR", "end of input"]),
+    );
+
+    match all_consuming(parser)(input) {
+        Ok((_, parsed_expr)) => Ok(parsed_expr),
+        Err(err) => Err(ParseError::from_nom(input, err)),
+    }
 }
 
-fn parse_union(input: &str) -> IResult<&str, SetExpr> {
-    // parsing the inside of the parentheses
-    let parser = tuple((parse_expr, tag(" OR "), parse_expr));
+// trailing whitespace followed by the end of the input
+pub fn parse_end<'a>(
+    expected: &'static [&'static str],
+) -> impl FnMut(&'a str) -> ParseResult<'a, ()> {
+    map(expect(expected, preceded(multispace0, eof)), |_| ())
+}
 
-    // dealing with the parentheses
-    let parser = delimited(char('('), parser, char(')'));
+// operators are reserved and cannot be used as set keys
+// TO and FROM separate the set expressions in a command
+const KEYWORDS: [&str; 7] = ["OR", "AND", "EXCEPT", "NOT", "TO", "FROM", "UNTIL"];
 
-    map(parser, |(left, _, right)| {
-        SetExpr::Union(Box::new(left), Box::new(right))
+fn parse_set(input: &str) -> ParseResult<'_, SetExpr> {
+    let whitespace_or_parentheses = |c: char| c.is_whitespace() || c == '(' || c == ')';
+    let parser = verify(take_till1(whitespace_or_parentheses), |s: &str| {
+        !KEYWORDS.contains(&s)
+    });
+    // nom map applies the parser to input, then applies the closure to the result
+    map(expect(&["set key"], parser), |s: &str| match s {
+        "ALL" | "*" => SetExpr::Universe,
+        _ => SetExpr::Set(s.to_string()),
     })(input)
 }
 
-fn parse_intersection(input: &str) -> IResult<&str, SetExpr> {
-    let parser = tuple((parse_expr, tag(" AND "), parse_expr));
+// a keyword must be followed by whitespace, a parenthesis or the end of input
+// so keys such as NOTES or ORDERS are still sets
+fn parse_keyword<'a>(keyword: &'static str) -> impl FnMut(&'a str) -> ParseResult<'a, &'a str> {
+    terminated(
+        tag(keyword),
+        peek(alt((multispace1, tag("("), tag(")"), eof))),
+    )
+}
 
-    let parser = delimited(char('('), parser, char(')'));
+// parentheses and NOT nest at most this deep, and no expression has more operators than this
+// on any path from the root, so parsing and every recursive walk of the tree stay shallow
+pub const MAX_NESTING_DEPTH: usize = 256;
 
-    map(parser, |(left, _, right)| {
-        SetExpr::Intersection(Box::new(left), Box::new(right))
-    })(input)
+// a parsed expression with the number of operators on its longest path
+type Parsed = (SetExpr, usize);
+
+// reported at the token that would go deeper than MAX_NESTING_DEPTH
+fn too_deep(input: &str) -> nom::Err<SyntaxError<'_>> {
+    nom::Err::Failure(SyntaxError {
+        input,
+        expected: vec!["at most 256 levels of nesting"],
+    })
 }
 
-fn parse_except(input: &str) -> IResult<&str, SetExpr> {
-    let parser = tuple((parse_expr, tag(" EXCEPT "), parse_expr));
+fn parse_not(input: &str, nesting: usize) -> ParseResult<'_, Parsed> {
+    let (rest, _) = expect(&["NOT"], parse_keyword("NOT"))(input)?;
+    if nesting >= MAX_NESTING_DEPTH {
+        return Err(too_deep(input));
+    }
+    let (rest, (inner, depth)) = parse_term(rest, nesting + 1)?;
+    if depth >= MAX_NESTING_DEPTH {
+        return Err(too_deep(input));
+    }
 
-    let parser = delimited(char('('), parser, char(')'));
+    Ok((rest, (SetExpr::Not(Box::new(inner)), depth + 1)))
+}
 
-    map(parser, |(left, _, right)| {
-        SetExpr::Except(Box::new(left), Box::new(right))
-    })(input)
+// an infix operator must be preceded by whitespace e.g. " OR "
+fn parse_operator<'a>(keyword: &'static str) -> impl FnMut(&'a str) -> ParseResult<'a, &'a str> {
+    preceded(multispace1, parse_keyword(keyword))
 }
 
-fn parse_parens(input: &str) -> IResult<&str, SetExpr> {
-    delimited(
-        preceded(multispace0, tag("(")),
-        parse_expr,
-        preceded(multispace0, tag(")")),
+fn parse_parens(input: &str, nesting: usize) -> ParseResult<'_, Parsed> {
+    let (rest, _) = preceded(multispace0, expect(&["("], tag("(")))(input)?;
+    if nesting >= MAX_NESTING_DEPTH {
+        return Err(too_deep(input));
+    }
+
+    terminated(
+        |input| parse_nested_expr(input, nesting + 1),
+        preceded(multispace0, expect(&[")", "AND", "EXCEPT", "OR"], tag(")"))),
+    )(rest)
+}
+
+// NOT binds tighter than any infix operator
+fn parse_term(input: &str, nesting: usize) -> ParseResult<'_, Parsed> {
+    preceded(
+        multispace0,
+        alt((
+            |input| parse_not(input, nesting),
+            |input| parse_parens(input, nesting),
+            map(parse_set, |expr| (expr, 0)),
+        )),
     )(input)
 }
 
-fn parse_term(input: &str) -> IResult<&str, SetExpr> {
-    alt((parse_parens, parse_set))(input)
+// AND and EXCEPT bind tighter than OR, chains are folded to the left
+// e.g. A AND B EXCEPT C is read as ((A AND B) EXCEPT C)
+fn parse_intersection_chain(input: &str, nesting: usize) -> ParseResult<'_, Parsed> {
+    let (mut input, (mut expr, mut depth)) = parse_term(input, nesting)?;
+
+    loop {
+        let (rest, operator) = match alt((parse_operator("AND"), parse_operator("EXCEPT")))(input) {
+            Ok(x) => x,
+            // no further operator - leave the remaining input for the caller
+            Err(nom::Err::Error(_)) => return Ok((input, (expr, depth))),
+            Err(err) => return Err(err),
+        };
+        let (rest, (right, right_depth)) = parse_term(rest, nesting)?;
+        depth = depth.max(right_depth) + 1;
+        if depth > MAX_NESTING_DEPTH {
+            return Err(too_deep(input));
+        }
+
+        expr = match operator {
+            "AND" => SetExpr::Intersection(Box::new(expr), Box::new(right)),
+            _ => SetExpr::Except(Box::new(expr), Box::new(right)),
+        };
+        input = rest;
+    }
 }
 
-pub fn parse_expr(input: &str) -> IResult<&str, SetExpr> {
-    alt((parse_union, parse_intersection, parse_except, parse_term))(input)
+// precedence climbing over the lowest precedence operator OR
+// e.g. A OR B AND C is read as (A OR (B AND C))
+pub fn parse_expr(input: &str) -> ParseResult<'_, SetExpr> {
+    map(|input| parse_nested_expr(input, 0), |(expr, _)| expr)(input)
 }
 
-pub fn resolve_set(
-    parsed_expression: SetExpr,
-    set_map: &HashMap<String, HashSet<String>>,
-) -> anyhow::Result<HashSet<String>> {
-    let result = match parsed_expression {
+// nesting counts the parentheses and NOT enclosing the input
+fn parse_nested_expr(input: &str, nesting: usize) -> ParseResult<'_, Parsed> {
+    let (mut input, (mut expr, mut depth)) = parse_intersection_chain(input, nesting)?;
+
+    loop {
+        let rest = match parse_operator("OR")(input) {
+            Ok((rest, _)) => rest,
+            Err(nom::Err::Error(_)) => return Ok((input, (expr, depth))),
+            Err(err) => return Err(err),
+        };
+        let (rest, (right, right_depth)) = parse_intersection_chain(rest, nesting)?;
+        depth = depth.max(right_depth) + 1;
+        if depth > MAX_NESTING_DEPTH {
+            return Err(too_deep(input));
+        }
+
+        expr = SetExpr::Union(Box::new(expr), Box::new(right));
+        input = rest;
+    }
+}
+
+// a resolved set held as handles into the registry it was evaluated against
+// a single key or ALL borrows the registry bitmap rather than copying it
+#[derive(Debug, Clone)]
+pub struct ResolvedSet<'r> {
+    registry: &'r SetRegistry,
+    handles: Cow<'r, RoaringBitmap>,
+}
+
+impl<'r> ResolvedSet<'r> {
+    pub fn from_handles(registry: &'r SetRegistry, handles: Cow<'r, RoaringBitmap>) -> Self {
+        ResolvedSet { registry, handles }
+    }
+
+    pub fn into_handles(self) -> Cow<'r, RoaringBitmap> {
+        self.handles
+    }
+
+    pub fn registry(&self) -> &'r SetRegistry {
+        self.registry
+    }
+
+    pub fn len(&self) -> usize {
+        self.handles.len() as usize
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.handles.is_empty()
+    }
+
+    pub fn contains(&self, id: &str) -> bool {
+        self.registry
+            .handle(id)
+            .is_some_and(|handle| self.handles.contains(handle))
+    }
+
+    pub fn handles(&self) -> &RoaringBitmap {
+        &self.handles
+    }
+
+    // ids borrowed from the registry, in handle order
+    pub fn iter(&self) -> impl Iterator<Item = &'r str> + '_ {
+        self.handles.iter().map(|handle| self.registry.name(handle))
+    }
+
+    pub fn to_names(&self) -> HashSet<String> {
+        self.registry.names_of(&self.handles)
+    }
+}
+
+// checks every key in the expression exists before evaluating it
+// so that all unknown keys are reported together
+pub fn resolve<'r>(
+    parsed_expression: &SetExpr,
+    registry: &'r SetRegistry,
+) -> Result<ResolvedSet<'r>> {
+    let unknown_keys = unknown_keys(parsed_expression, registry);
+
+    if !unknown_keys.is_empty() {
+        return Err(RhubarbError::UnknownKeys(unknown_keys));
+    }
+
+    Ok(evaluate(parsed_expression, registry))
+}
+
+// evaluates without checking keys, keys missing from the registry match nothing
+// the expression is only borrowed so it can be evaluated again, e.g. against a refreshed registry
+pub fn evaluate<'r>(parsed_expression: &SetExpr, registry: &'r SetRegistry) -> ResolvedSet<'r> {
+    assert_nesting_depth(parsed_expression);
+    ResolvedSet {
+        registry,
+        handles: evaluate_handles(parsed_expression, registry),
+    }
+}
+
+// registries with fewer ids are evaluated sequentially, forking would cost more than it saves
+pub const PARALLEL_EVALUATION_MIN_IDS: usize = 50_000;
+
+// evaluate, or evaluate_parallel once the registry reaches PARALLEL_EVALUATION_MIN_IDS
+pub fn evaluate_adaptive<'r>(
+    parsed_expression: &SetExpr,
+    registry: &'r SetRegistry,
+) -> ResolvedSet<'r> {
+    if registry.id_count() >= PARALLEL_EVALUATION_MIN_IDS {
+        evaluate_parallel(parsed_expression, registry)
+    } else {
+        evaluate(parsed_expression, registry)
+    }
+}
+
+// as evaluate, with the operands of OR, AND and EXCEPT evaluated concurrently
+// only operators whose operands are both compound fork, a leaf is borrowed at no cost
+// worthwhile for wide expressions over large registries, see evaluate_adaptive
+pub fn evaluate_parallel<'r>(
+    parsed_expression: &SetExpr,
+    registry: &'r SetRegistry,
+) -> ResolvedSet<'r> {
+    assert_nesting_depth(parsed_expression);
+    ResolvedSet {
+        registry,
+        handles: evaluate_handles_parallel(parsed_expression, registry),
+    }
+}
+
+pub fn resolve_set(parsed_expression: SetExpr, registry: &SetRegistry) -> Result<HashSet<String>> {
+    Ok(resolve(&parsed_expression, registry)?.to_names())
+}
+
+// re-resolves the expression of a stored statement, e.g. after a registry refresh
+// keys that have since left the registry match nothing rather than failing
+pub fn resolve_stored_set(parsed_expression: &SetExpr, registry: &SetRegistry) -> HashSet<String> {
+    evaluate(parsed_expression, registry).to_names()
+}
+
+// whether a single id is in the resolved set, without resolving the whole expression
+// keys missing from the registry match nothing, as in resolve_stored_set
+pub fn set_contains(parsed_expression: &SetExpr, id: &str, registry: &SetRegistry) -> bool {
+    match parsed_expression {
+        SetExpr::Set(key) => registry.is_member(key, id),
         SetExpr::Union(left, right) => {
-            let left_set = resolve_set(*left, set_map)?;
-            let right_set = resolve_set(*right, set_map)?;
-            anyhow::Ok(
-                left_set
-                    .union(&right_set)
-                    .map(|s| s.to_string())
-                    .collect::<HashSet<String>>(),
-            )
+            set_contains(left, id, registry) || set_contains(right, id, registry)
         }
         SetExpr::Intersection(left, right) => {
-            let left_set = resolve_set(*left, set_map)?;
-            let right_set = resolve_set(*right, set_map)?;
-            anyhow::Ok(
-                left_set
-                    .intersection(&right_set)
-                    .map(|s| s.to_string())
-                    .collect::<HashSet<String>>(),
-            )
+            set_contains(left, id, registry) && set_contains(right, id, registry)
         }
         SetExpr::Except(left, right) => {
-            let left_set = resolve_set(*left, set_map)?;
-            let right_set = resolve_set(*right, set_map)?;
-            anyhow::Ok(
-                left_set
-                    .difference(&right_set)
-                    .map(|s| s.to_string())
-                    .collect::<HashSet<String>>(),
+            set_contains(left, id, registry) && !set_contains(right, id, registry)
+        }
+        SetExpr::Not(inner) => registry.contains_id(id) && !set_contains(inner, id, registry),
+        SetExpr::Universe => registry.contains_id(id),
+    }
+}
+
+// keys referenced by the expression that are missing from the registry, with suggestions
+pub fn unknown_keys(parsed_expression: &SetExpr, registry: &SetRegistry) -> Vec<UnknownKey> {
+    parsed_expression
+        .keys()
+        .into_iter()
+        .filter(|key| !registry.contains_key(key))
+        .map(|key| UnknownKey {
+            key: key.to_string(),
+            suggestions: suggest::suggest_keys(key, registry),
+        })
+        .collect()
+}
+
+// set operations run on the bitmaps of id handles, names are only looked up at the end
+// leaves borrow from the registry, only operators allocate a new bitmap
+fn evaluate_handles<'r>(
+    parsed_expression: &SetExpr,
+    registry: &'r SetRegistry,
+) -> Cow<'r, RoaringBitmap> {
+    match parsed_expression {
+        SetExpr::Union(left, right) => Cow::Owned(
+            evaluate_handles(left, registry).as_ref() | evaluate_handles(right, registry).as_ref(),
+        ),
+        SetExpr::Intersection(left, right) => Cow::Owned(
+            evaluate_handles(left, registry).as_ref() & evaluate_handles(right, registry).as_ref(),
+        ),
+        SetExpr::Except(left, right) => Cow::Owned(
+            evaluate_handles(left, registry).as_ref() - evaluate_handles(right, registry).as_ref(),
+        ),
+        SetExpr::Not(inner) => {
+            Cow::Owned(registry.id_bitmap() - evaluate_handles(inner, registry).as_ref())
+        }
+        SetExpr::Universe => Cow::Borrowed(registry.id_bitmap()),
+        SetExpr::Set(key) => registry
+            .key_bitmap(key)
+            .map_or_else(|| Cow::Owned(RoaringBitmap::new()), Cow::Borrowed),
+    }
+}
+
+fn evaluate_handles_parallel<'r>(
+    parsed_expression: &SetExpr,
+    registry: &'r SetRegistry,
+) -> Cow<'r, RoaringBitmap> {
+    let join = |left: &SetExpr, right: &SetExpr| {
+        if left.is_leaf() || right.is_leaf() {
+            (
+                evaluate_handles_parallel(left, registry),
+                evaluate_handles_parallel(right, registry),
+            )
+        } else {
+            rayon::join(
+                || evaluate_handles_parallel(left, registry),
+                || evaluate_handles_parallel(right, registry),
             )
         }
-        SetExpr::Set(key) => set_map
-            .get(&key)
-            .ok_or(anyhow::anyhow!("Key not found: {}", key))
-            .map(|set| set.clone()),
     };
 
-    result
+    match parsed_expression {
+        SetExpr::Union(left, right) => {
+            let (left, right) = join(left, right);
+            Cow::Owned(left.as_ref() | right.as_ref())
+        }
+        SetExpr::Intersection(left, right) => {
+            let (left, right) = join(left, right);
+            Cow::Owned(left.as_ref() & right.as_ref())
+        }
+        SetExpr::Except(left, right) => {
+            let (left, right) = join(left, right);
+            Cow::Owned(left.as_ref() - right.as_ref())
+        }
+        SetExpr::Not(inner) => {
+            Cow::Owned(registry.id_bitmap() - evaluate_handles_parallel(inner, registry).as_ref())
+        }
+        SetExpr::Set(_) | SetExpr::Universe => evaluate_handles(parsed_expression, registry),
+    }
+}
+
+// collects every sub-expression (including the root) whose resolved set contains the id
+// outermost expressions come first
+// keys missing from the registry match nothing, as when stored statements are re-resolved
+pub fn matching_subexprs(
+    parsed_expression: &SetExpr,
+    id: &str,
+    registry: &SetRegistry,
+) -> Vec<SetExpr> {
+    let mut matches = Vec::new();
+
+    if set_contains(parsed_expression, id, registry) {
+        matches.push(parsed_expression.clone());
+    }
+
+    match parsed_expression {
+        SetExpr::Union(left, right)
+        | SetExpr::Intersection(left, right)
+        | SetExpr::Except(left, right) => {
+            matches.extend(matching_subexprs(left, id, registry));
+            matches.extend(matching_subexprs(right, id, registry));
+        }
+        SetExpr::Not(inner) => matches.extend(matching_subexprs(inner, id, registry)),
+        SetExpr::Set(_) | SetExpr::Universe => {}
+    }
+
+    matches
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::collections::HashMap;
 
     #[test]
     fn test_resolve_set_single_set() {
@@ -157,9 +602,11 @@ mod tests {
 
         map.insert("division:product_design3".to_string(), set.clone());
 
+        let registry = SetRegistry::from(map);
+
         // act
         let (_, parsed_expr) = parse_expr("division:product_design3").unwrap();
-        let result = resolve_set(parsed_expr, &map).unwrap();
+        let result = resolve_set(parsed_expr, &registry).unwrap();
 
         assert_eq!(
             result, set,
@@ -183,9 +630,11 @@ mod tests {
         map.insert("A".to_string(), set_a);
         map.insert("B".to_string(), set_b);
 
+        let registry = SetRegistry::from(map);
+
         // act
         let (_, parsed_expr) = parse_expr("(A OR B)").unwrap();
-        let result = resolve_set(parsed_expr, &map).unwrap();
+        let result = resolve_set(parsed_expr, &registry).unwrap();
 
         // assert
         assert_eq!(
@@ -211,9 +660,11 @@ mod tests {
         map.insert("A".to_string(), set_a);
         map.insert("B".to_string(), set_b);
 
+        let registry = SetRegistry::from(map);
+
         // act
         let (_, parsed_expr) = parse_expr("(A AND B)").unwrap();
-        let result = resolve_set(parsed_expr, &map).unwrap();
+        let result = resolve_set(parsed_expr, &registry).unwrap();
 
         // assert
         assert_eq!(
@@ -239,9 +690,11 @@ mod tests {
         map.insert("A".to_string(), set_a);
         map.insert("B".to_string(), set_b);
 
+        let registry = SetRegistry::from(map);
+
         // act
         let (_, parsed_expr) = parse_expr("(A EXCEPT B)").unwrap();
-        let result = resolve_set(parsed_expr, &map).unwrap();
+        let result = resolve_set(parsed_expr, &registry).unwrap();
 
         // assert
         assert_eq!(
@@ -266,9 +719,11 @@ mod tests {
         map.insert("B".to_string(), set_b);
         map.insert("C".to_string(), set_c);
 
+        let registry = SetRegistry::from(map);
+
         // act
         let (_, parsed_expr) = parse_expr("(A OR (B AND C))").unwrap();
-        let result = resolve_set(parsed_expr, &map).unwrap();
+        let result = resolve_set(parsed_expr, &registry).unwrap();
 
         // assert
         assert_eq!(
@@ -295,9 +750,11 @@ mod tests {
         map.insert("B".to_string(), set_b);
         map.insert("C".to_string(), set_c);
 
+        let registry = SetRegistry::from(map);
+
         // act
         let (_, parsed_expr) = parse_expr("((B OR C) OR A)").unwrap();
-        let result = resolve_set(parsed_expr, &map).unwrap();
+        let result = resolve_set(parsed_expr, &registry).unwrap();
 
         // assert
         assert_eq!(
@@ -319,26 +776,473 @@ mod tests {
         map.insert("B".to_string(), set_b);
         map.insert("C".to_string(), set_c);
 
+        let registry = SetRegistry::from(map);
+
         // act and assert
-        let (_, parsed_expr) = parse_expr("A OR B AND C").unwrap();
         assert!(
-            resolve_set(parsed_expr, &map).is_err(),
-            "Should return an error for bad syntax 'A OR B AND C'"
+            parse_expr("(A OR B AND C").is_err(),
+            "Should return an error for unbalanced parentheses '(A OR B AND C'"
+        );
+
+        assert!(
+            parse_expr("(A OR OR B)").is_err(),
+            "Should return an error for repeated operators '(A OR OR B)'"
         );
 
-        let (_, parsed_expr) = parse_expr("(A OR B AND C)").unwrap();
         assert!(
-            resolve_set(parsed_expr, &map).is_err(),
-            "Should return an error for bad syntax '(A OR B AND C)'"
+            parse_expr("A OR").is_err(),
+            "Should return an error for a dangling operator 'A OR'"
         );
 
         let (_, parsed_expr) = parse_expr("AOR B").unwrap();
         assert!(
-            resolve_set(parsed_expr, &map).is_err(),
+            resolve_set(parsed_expr, &registry).is_err(),
             "Should return an error for bad syntax 'AOR B'"
         );
     }
 
+    // A OR B AND C
+    #[test]
+    fn test_resolve_set_precedence() {
+        // arrange
+        let mut map = HashMap::new();
+
+        let set_a: HashSet<String> = "1 2".split_whitespace().map(|s| s.to_string()).collect();
+        let set_b: HashSet<String> = "2 3".split_whitespace().map(|s| s.to_string()).collect();
+        let set_c: HashSet<String> = "3 4".split_whitespace().map(|s| s.to_string()).collect();
+        let set_result: HashSet<String> =
+            "1 2 3".split_whitespace().map(|s| s.to_string()).collect();
+
+        map.insert("A".to_string(), set_a);
+        map.insert("B".to_string(), set_b);
+        map.insert("C".to_string(), set_c);
+
+        let registry = SetRegistry::from(map);
+
+        // act
+        let (leftover, parsed_expr) = parse_expr("A OR B AND C").unwrap();
+
+        // assert
+        assert_eq!(leftover, "");
+        assert_eq!(parsed_expr.to_string(), "(A OR (B AND C))");
+        assert_eq!(
+            resolve_set(parsed_expr, &registry).unwrap(),
+            set_result,
+            "Result should be equal to the evaluation of the expression 'A OR (B AND C)'"
+        );
+
+        let (_, parsed_expr) = parse_expr("A AND B EXCEPT C OR C").unwrap();
+        assert_eq!(parsed_expr.to_string(), "(((A AND B) EXCEPT C) OR C)");
+    }
+
+    // (A OR B OR C OR D)
+    #[test]
+    fn test_resolve_set_chain() {
+        // arrange
+        let mut map = HashMap::new();
+
+        let set_a: HashSet<String> = "1".split_whitespace().map(|s| s.to_string()).collect();
+        let set_b: HashSet<String> = "2".split_whitespace().map(|s| s.to_string()).collect();
+        let set_c: HashSet<String> = "3".split_whitespace().map(|s| s.to_string()).collect();
+        let set_d: HashSet<String> = "4".split_whitespace().map(|s| s.to_string()).collect();
+        let set_result: HashSet<String> = "1 2 3 4"
+            .split_whitespace()
+            .map(|s| s.to_string())
+            .collect();
+
+        map.insert("A".to_string(), set_a);
+        map.insert("B".to_string(), set_b);
+        map.insert("C".to_string(), set_c);
+        map.insert("D".to_string(), set_d);
+
+        let registry = SetRegistry::from(map);
+
+        // act
+        let (leftover, parsed_expr) = parse_expr("(A OR B OR C OR D)").unwrap();
+
+        // assert
+        assert_eq!(leftover, "");
+        assert_eq!(parsed_expr.to_string(), "(((A OR B) OR C) OR D)");
+        assert_eq!(resolve_set(parsed_expr, &registry).unwrap(), set_result);
+    }
+
+    // ALL EXCEPT A, NOT A AND B
+    #[test]
+    fn test_resolve_set_universe_and_not() {
+        // arrange
+        let mut map = HashMap::new();
+
+        let set_a: HashSet<String> = "1 2".split_whitespace().map(|s| s.to_string()).collect();
+        let set_b: HashSet<String> = "2 3 4".split_whitespace().map(|s| s.to_string()).collect();
+        let set_universe: HashSet<String> = "1 2 3 4"
+            .split_whitespace()
+            .map(|s| s.to_string())
+            .collect();
+        let set_complement: HashSet<String> =
+            "3 4".split_whitespace().map(|s| s.to_string()).collect();
+
+        map.insert("A".to_string(), set_a);
+        map.insert("B".to_string(), set_b);
+
+        let registry = SetRegistry::from(map);
+
+        // act and assert
+        let (_, parsed_expr) = parse_expr("*").unwrap();
+        assert_eq!(resolve_set(parsed_expr, &registry).unwrap(), set_universe);
+
+        let (_, parsed_expr) = parse_expr("ALL EXCEPT A").unwrap();
+        assert_eq!(resolve_set(parsed_expr, &registry).unwrap(), set_complement);
+
+        let (_, parsed_expr) = parse_expr("NOT A").unwrap();
+        assert_eq!(resolve_set(parsed_expr, &registry).unwrap(), set_complement);
+
+        // NOT binds tighter than AND
+        let (_, parsed_expr) = parse_expr("NOT A AND B").unwrap();
+        assert_eq!(parsed_expr.to_string(), "(NOT A AND B)");
+        assert_eq!(resolve_set(parsed_expr, &registry).unwrap(), set_complement);
+
+        let (_, parsed_expr) = parse_expr("NOT(A OR B)").unwrap();
+        assert!(resolve_set(parsed_expr, &registry).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_parse_not_requires_boundary() {
+        let (_, parsed_expr) = parse_expr("NOTES").unwrap();
+        assert_eq!(parsed_expr, SetExpr::Set("NOTES".to_string()));
+
+        assert!(
+            parse_expr("NOT").is_err(),
+            "Should return an error for NOT without an operand"
+        );
+    }
+
+    #[test]
+    fn test_parse_set_expr_errors() {
+        let err = parse_set_expr("(A OR B").unwrap_err();
+        assert_eq!(err.offset, 7);
+        assert_eq!(err.expected, vec![")", "AND", "EXCEPT", "OR"]);
+        assert_eq!(err.found, "end of input");
+
+        let err = parse_set_expr("A OR  AND B").unwrap_err();
+        assert_eq!(err.offset, 6);
+        assert_eq!(err.column, 7);
+        assert_eq!(err.found, "'AND'");
+        assert!(err.expected.contains(&"set key".to_string()));
+
+        let err = parse_set_expr("A B").unwrap_err();
+        assert_eq!(err.offset, 2);
+        assert_eq!(err.snippet, "A B\n  ^");
+    }
+
+    #[test]
+    fn test_parse_deeply_nested_parens_is_err() {
+        // arrange
+        let at_limit = format!(
+            "{}A{}",
+            "(".repeat(MAX_NESTING_DEPTH),
+            ")".repeat(MAX_NESTING_DEPTH)
+        );
+        let too_deep = format!("{}A{}", "(".repeat(100_000), ")".repeat(100_000));
+
+        // act
+        let parsed = parse_set_expr(&at_limit);
+        let err = parse_set_expr(&too_deep).unwrap_err();
+
+        // assert
+        assert_eq!(parsed.unwrap(), SetExpr::Set("A".to_string()));
+        assert_eq!(err.offset, MAX_NESTING_DEPTH);
+        assert_eq!(err.expected, vec!["at most 256 levels of nesting"]);
+        assert!(err.found.starts_with("'("));
+    }
+
+    #[test]
+    fn test_parse_deep_not_chain_is_err() {
+        // arrange
+        let at_limit = format!("{}A", "NOT ".repeat(MAX_NESTING_DEPTH));
+        let too_deep = format!("{}A", "NOT ".repeat(200_000));
+
+        // act
+        let parsed = parse_set_expr(&at_limit).unwrap();
+        let err = parse_set_expr(&too_deep).unwrap_err();
+
+        // assert
+        assert_eq!(parsed.depth(), MAX_NESTING_DEPTH);
+        assert_eq!(err.offset, 4 * MAX_NESTING_DEPTH);
+        assert_eq!(err.found, "'NOT'");
+    }
+
+    #[test]
+    fn test_parse_long_operator_chain_is_bounded() {
+        // arrange
+        let at_limit = vec!["A"; MAX_NESTING_DEPTH + 1].join(" OR ");
+        let too_deep = vec!["A"; 100_000].join(" AND ");
+        let mut map = HashMap::new();
+        map.insert("A".to_string(), HashSet::from(["1".to_string()]));
+        let registry = SetRegistry::from(map);
+
+        // act
+        let parsed = parse_set_expr(&at_limit).unwrap();
+        let err = parse_set_expr(&too_deep).unwrap_err();
+
+        // assert
+        assert_eq!(parsed.depth(), MAX_NESTING_DEPTH);
+        assert_eq!(evaluate(&parsed, &registry).len(), 1);
+        assert_eq!(parsed.canonical_hash(), parsed.clone().canonical_hash());
+        assert_eq!(err.offset, 2 + 6 * MAX_NESTING_DEPTH);
+        assert_eq!(err.found, "'AND'");
+    }
+
+    #[test]
+    fn test_set_expr_serde_round_trip() {
+        // arrange
+        let nested = format!("{}A{}", "NOT (".repeat(128), ")".repeat(128));
+        let chain = vec!["A"; MAX_NESTING_DEPTH + 1].join(" EXCEPT ");
+        let exprs = [
+            parse_set_expr("department:tax AND designation:partner OR NOT ALL").unwrap(),
+            parse_set_expr(&nested).unwrap(),
+            parse_set_expr(&chain).unwrap(),
+        ];
+
+        for expr in exprs {
+            // act
+            let json = serde_json::to_string(&expr).unwrap();
+            let loaded: SetExpr = serde_json::from_str(&json).unwrap();
+
+            // assert
+            assert_eq!(loaded, expr);
+        }
+        assert!(serde_json::from_str::<SetExpr>("\"A OR\"").is_err());
+    }
+
+    #[test]
+    #[should_panic(expected = "nests deeper than 256 levels")]
+    fn test_evaluate_rejects_expression_deeper_than_limit() {
+        // arrange
+        let mut expr = SetExpr::Universe;
+        for _ in 0..=MAX_NESTING_DEPTH {
+            expr = SetExpr::Not(Box::new(expr));
+        }
+        let registry = SetRegistry::from(HashMap::new());
+
+        // act
+        evaluate(&expr, &registry);
+    }
+
+    #[test]
+    fn test_resolve_set_reports_all_unknown_keys() {
+        // arrange
+        let mut map = HashMap::new();
+
+        let set_a: HashSet<String> = "1 2".split_whitespace().map(|s| s.to_string()).collect();
+        let set_b: HashSet<String> = "2 3".split_whitespace().map(|s| s.to_string()).collect();
+
+        map.insert("designation:senior".to_string(), set_a);
+        map.insert("department:tax".to_string(), set_b);
+
+        let registry = SetRegistry::from(map);
+
+        // act
+        let (_, parsed_expr) =
+            parse_expr("designation:seniro AND (department:txa OR designation:seniro)").unwrap();
+        let err = resolve_set(parsed_expr, &registry).unwrap_err();
+
+        // assert
+        assert_eq!(
+            err,
+            RhubarbError::UnknownKeys(vec![
+                UnknownKey {
+                    key: "designation:seniro".to_string(),
+                    suggestions: vec!["designation:senior".to_string()],
+                },
+                UnknownKey {
+                    key: "department:txa".to_string(),
+                    suggestions: vec!["department:tax".to_string()],
+                },
+            ])
+        );
+    }
+
+    #[test]
+    fn test_parse_malformed_input_is_err() {
+        // arrange
+        let mut map = HashMap::new();
+
+        let set_a: HashSet<String> = "1 2".split_whitespace().map(|s| s.to_string()).collect();
+
+        map.insert("A".to_string(), set_a);
+
+        let registry = SetRegistry::from(map);
+
+        // act and assert
+        for input in [
+            "",
+            " ",
+            "(",
+            ")",
+            "(A OR",
+            "A OR",
+            "A AND (",
+            "((A)",
+            "A)",
+            "NOT",
+            "NOT (",
+            "OR A",
+            "A EXCEPT",
+            "ü OR (",
+            "A \u{a0}B",
+            "A\n OR",
+        ] {
+            assert!(
+                matches!(parse(input, &registry), Err(RhubarbError::Parse(_))),
+                "Should return a parse error for '{}'",
+                input
+            );
+        }
+    }
+
+    #[test]
+    fn test_matching_subexprs() {
+        // arrange
+        let mut map = HashMap::new();
+
+        let set_a: HashSet<String> = "1 2".split_whitespace().map(|s| s.to_string()).collect();
+        let set_b: HashSet<String> = "2 3".split_whitespace().map(|s| s.to_string()).collect();
+        let set_c: HashSet<String> = "3 4".split_whitespace().map(|s| s.to_string()).collect();
+
+        map.insert("A".to_string(), set_a);
+        map.insert("B".to_string(), set_b);
+        map.insert("C".to_string(), set_c);
+
+        let registry = SetRegistry::from(map);
+
+        // act
+        let (_, parsed_expr) = parse_expr("(A OR (B EXCEPT C))").unwrap();
+        let matches = matching_subexprs(&parsed_expr, "2", &registry);
+
+        // assert
+        let rendered: Vec<String> = matches.iter().map(|expr| expr.to_string()).collect();
+        assert_eq!(
+            rendered,
+            vec!["(A OR (B EXCEPT C))", "A", "(B EXCEPT C)", "B"]
+        );
+    }
+
+    #[test]
+    fn test_resolve_set_large_registry() {
+        // arrange - 100k ids, each holding multiple:n for every n in 2..=5 dividing it
+        let mut registry = SetRegistry::new();
+        for i in 0..100_000u32 {
+            let id = format!("user{}", i);
+            registry.insert_id(&id);
+            for n in 2..=5 {
+                if i % n == 0 {
+                    registry.insert(format!("multiple:{}", n), id.clone());
+                }
+            }
+        }
+
+        // act
+        let result = parse("multiple:2 AND multiple:3 EXCEPT multiple:5", &registry).unwrap();
+        let complement = parse("NOT (multiple:2 OR multiple:3)", &registry).unwrap();
+
+        // assert
+        let expected = (0..100_000u32).filter(|i| i % 6 == 0 && i % 5 != 0).count();
+        assert_eq!(result.len(), expected);
+        assert!(result.contains("user6") && !result.contains("user30"));
+        assert_eq!(
+            complement.len(),
+            (0..100_000u32).filter(|i| i % 2 != 0 && i % 3 != 0).count()
+        );
+    }
+
+    #[test]
+    fn test_evaluate_borrowed_expression_against_registries() {
+        // arrange
+        let mut map = HashMap::new();
+        map.insert(
+            "A".to_string(),
+            HashSet::from(["1".to_string(), "2".to_string()]),
+        );
+        map.insert("B".to_string(), HashSet::from(["2".to_string()]));
+        let registry = SetRegistry::from(map);
+
+        let mut refreshed = registry.clone();
+        refreshed.insert_id("3");
+        refreshed.insert("A".to_string(), "3".to_string());
+        refreshed.delete("B", "2");
+
+        let parsed_expr = parse_set_expr("A EXCEPT B").unwrap();
+
+        // act
+        let before = evaluate(&parsed_expr, &registry);
+        let after = evaluate(&parsed_expr, &refreshed);
+
+        // assert
+        assert_eq!(before.iter().collect::<Vec<_>>(), vec!["1"]);
+        assert_eq!(after.len(), 3);
+        assert!(after.contains("2") && after.contains("3") && !after.contains("4"));
+        assert_eq!(
+            after.to_names(),
+            resolve_stored_set(&parsed_expr, &refreshed)
+        );
+        assert!(matches!(
+            resolve(&parse_set_expr("A OR C").unwrap(), &registry),
+            Err(RhubarbError::UnknownKeys(_))
+        ));
+        assert!(evaluate(&parse_set_expr("C").unwrap(), &registry).is_empty());
+    }
+
+    #[test]
+    fn test_evaluate_parallel_matches_evaluate() {
+        // arrange
+        let mut registry = SetRegistry::new();
+        for i in 0..1_000u32 {
+            let id = i.to_string();
+            registry.insert_id(&id);
+            for n in [2, 3, 5, 7] {
+                if i % n == 0 {
+                    registry.insert(format!("multiple:{}", n), id.clone());
+                }
+            }
+        }
+
+        // act and assert
+        for input in [
+            "multiple:2 OR multiple:3 AND multiple:5",
+            "(multiple:2 OR multiple:7) EXCEPT (multiple:3 AND NOT multiple:5)",
+            "NOT (multiple:2 OR multiple:3) AND ALL",
+            "multiple:7",
+        ] {
+            let parsed_expr = parse_set_expr(input).unwrap();
+            assert_eq!(
+                evaluate_parallel(&parsed_expr, &registry).handles(),
+                evaluate(&parsed_expr, &registry).handles(),
+                "Parallel evaluation should match for '{}'",
+                input
+            );
+        }
+    }
+
+    #[test]
+    fn test_canonical_hash() {
+        let expr = |input| parse_set_expr(input).unwrap();
+
+        let a = expr("(designation:partner OR designation:senior) AND department:tax");
+        let b = expr("department:tax AND (designation:senior OR designation:partner)");
+        assert_eq!(a.canonical_hash(), b.canonical_hash());
+        assert!(a.canonical_eq(&b));
+
+        // EXCEPT is not commutative
+        let c = expr("A EXCEPT B");
+        let d = expr("B EXCEPT A");
+        assert_ne!(c.canonical_hash(), d.canonical_hash());
+        assert!(!c.canonical_eq(&d));
+        assert_ne!(
+            expr("A OR B").canonical_hash(),
+            expr("A AND B").canonical_hash()
+        );
+    }
+
     // (A OR ((C AND B) OR D))
     #[test]
     fn test_resolve_set_deeply_nested() {
@@ -359,9 +1263,11 @@ mod tests {
         map.insert("C".to_string(), set_c);
         map.insert("D".to_string(), set_d);
 
+        let registry = SetRegistry::from(map);
+
         // act
         let (_, parsed_expr) = parse_expr("(A OR ((C AND B) OR D))").unwrap();
-        let result = resolve_set(parsed_expr, &map).unwrap();
+        let result = resolve_set(parsed_expr, &registry).unwrap();
 
         // assert
         assert_eq!(
@@ -0,0 +1,207 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::database::AtomicPermission;
+use crate::error::{Result, RhubarbError};
+
+// permissions may imply other permissions e.g. ADMIN implies WRITE implies READ
+// holding a permission means holding everything it implies, transitively
+#[derive(Debug, Clone, Default)]
+pub struct PermissionHierarchy {
+    // permission maps to the permissions it directly implies
+    implications: HashMap<String, Vec<String>>,
+}
+
+impl PermissionHierarchy {
+    // every permission in the graph must be valid and the graph must be acyclic
+    pub fn new(
+        implications: HashMap<String, Vec<String>>,
+        valid_permissions: &[String],
+    ) -> Result<PermissionHierarchy> {
+        for (permission, implied) in &implications {
+            for name in std::iter::once(permission).chain(implied) {
+                if !valid_permissions.contains(name) {
+                    return Err(RhubarbError::InvalidPermission(name.clone()));
+                }
+            }
+        }
+
+        let hierarchy = PermissionHierarchy { implications };
+
+        let mut finished = HashSet::new();
+        let mut keys: Vec<&String> = hierarchy.implications.keys().collect();
+        keys.sort();
+        for permission in keys {
+            hierarchy.check_acyclic(permission, &mut Vec::new(), &mut finished)?;
+        }
+
+        Ok(hierarchy)
+    }
+
+    pub fn implications(&self) -> &HashMap<String, Vec<String>> {
+        &self.implications
+    }
+
+    // depth first search, a permission already on the path means a cycle
+    fn check_acyclic<'a>(
+        &'a self,
+        permission: &'a String,
+        path: &mut Vec<&'a String>,
+        finished: &mut HashSet<&'a String>,
+    ) -> Result<()> {
+        if finished.contains(permission) {
+            return Ok(());
+        }
+        if let Some(start) = path.iter().position(|p| *p == permission) {
+            let cycle = path[start..]
+                .iter()
+                .chain(std::iter::once(&permission))
+                .map(|p| p.to_string())
+                .collect();
+            return Err(RhubarbError::CyclicPermissionImplication { cycle });
+        }
+
+        path.push(permission);
+        for implied in self.implications.get(permission).into_iter().flatten() {
+            self.check_acyclic(implied, path, finished)?;
+        }
+        path.pop();
+        finished.insert(permission);
+
+        Ok(())
+    }
+
+    // the permission and everything it implies
+    pub fn implied(&self, permission: &str) -> Vec<String> {
+        let mut implied = vec![permission.to_string()];
+        let mut i = 0;
+
+        while i < implied.len() {
+            for next in self.implications.get(&implied[i]).into_iter().flatten() {
+                if !implied.contains(next) {
+                    implied.push(next.clone());
+                }
+            }
+            i += 1;
+        }
+
+        implied
+    }
+
+    // the permission and every permission that implies it
+    pub fn implying(&self, permission: &str) -> Vec<String> {
+        let mut implying = vec![permission.to_string()];
+        let mut keys: Vec<&String> = self.implications.keys().collect();
+        keys.sort();
+
+        for candidate in keys {
+            if candidate != permission && self.implied(candidate).iter().any(|p| p == permission) {
+                implying.push(candidate.clone());
+            }
+        }
+
+        implying
+    }
+
+    // a granted permission also grants everything it implies
+    pub fn expand_implied(
+        &self,
+        atomics: impl IntoIterator<Item = AtomicPermission>,
+    ) -> HashSet<AtomicPermission> {
+        self.expand(atomics, |permission| self.implied(permission))
+    }
+
+    // a denied permission also denies everything that implies it
+    pub fn expand_implying(
+        &self,
+        atomics: impl IntoIterator<Item = AtomicPermission>,
+    ) -> HashSet<AtomicPermission> {
+        self.expand(atomics, |permission| self.implying(permission))
+    }
+
+    fn expand(
+        &self,
+        atomics: impl IntoIterator<Item = AtomicPermission>,
+        related: impl Fn(&str) -> Vec<String>,
+    ) -> HashSet<AtomicPermission> {
+        let mut cache: HashMap<String, Vec<String>> = HashMap::new();
+        let mut expanded = HashSet::new();
+
+        for atomic in atomics {
+            let permissions = cache
+                .entry(atomic.permission.clone())
+                .or_insert_with(|| related(&atomic.permission));
+
+            for permission in permissions.iter() {
+                expanded.insert(AtomicPermission {
+                    user_id: atomic.user_id.clone(),
+                    asset_id: atomic.asset_id.clone(),
+                    permission: permission.clone(),
+                });
+            }
+        }
+
+        expanded
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn permissions(names: &[&str]) -> Vec<String> {
+        names.iter().map(|name| name.to_string()).collect()
+    }
+
+    fn hierarchy(edges: &[(&str, &[&str])]) -> Result<PermissionHierarchy> {
+        let implications = edges
+            .iter()
+            .map(|(permission, implied)| (permission.to_string(), permissions(implied)))
+            .collect();
+        PermissionHierarchy::new(implications, &permissions(&["READ", "WRITE", "ADMIN"]))
+    }
+
+    #[test]
+    fn test_implied_and_implying() {
+        let hierarchy = hierarchy(&[("ADMIN", &["WRITE"]), ("WRITE", &["READ"])]).unwrap();
+
+        assert_eq!(
+            hierarchy.implied("ADMIN"),
+            permissions(&["ADMIN", "WRITE", "READ"])
+        );
+        assert_eq!(hierarchy.implied("READ"), permissions(&["READ"]));
+        assert_eq!(
+            hierarchy.implying("READ"),
+            permissions(&["READ", "ADMIN", "WRITE"])
+        );
+        assert_eq!(hierarchy.implying("ADMIN"), permissions(&["ADMIN"]));
+    }
+
+    #[test]
+    fn test_cycle_is_rejected() {
+        let err = hierarchy(&[
+            ("ADMIN", &["WRITE"]),
+            ("WRITE", &["READ"]),
+            ("READ", &["ADMIN"]),
+        ])
+        .unwrap_err();
+
+        assert!(matches!(
+            err,
+            RhubarbError::CyclicPermissionImplication { cycle }
+                if cycle == permissions(&["ADMIN", "WRITE", "READ", "ADMIN"])
+        ));
+        assert!(matches!(
+            hierarchy(&[("READ", &["READ"])]),
+            Err(RhubarbError::CyclicPermissionImplication { cycle })
+                if cycle == permissions(&["READ", "READ"])
+        ));
+    }
+
+    #[test]
+    fn test_unknown_permission_is_rejected() {
+        assert!(matches!(
+            hierarchy(&[("ADMIN", &["DELETE"])]),
+            Err(RhubarbError::InvalidPermission(name)) if name == "DELETE"
+        ));
+    }
+}
@@ -0,0 +1,855 @@
+use std::{
+    collections::{BTreeSet, HashMap, HashSet},
+    fmt,
+    path::Path,
+};
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    access_matrix::MatrixScope,
+    database::{AtomicPermission, Database, Permission, SetRegistry},
+    database_pipeline::load_set_registry_from_csv,
+    error::{Result, RhubarbError},
+    history::RegistryChange,
+    parse_set,
+    set_cache::SetCache,
+};
+
+// membership changes between two versions of a registry, every list is sorted
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct RegistryDiff {
+    pub ids_added: Vec<String>,
+    pub ids_removed: Vec<String>,
+    // keys that appeared, disappeared or gained / lost members
+    pub keys_changed: Vec<KeyChange>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct KeyChange {
+    pub key: String,
+    pub members_added: Vec<String>,
+    pub members_removed: Vec<String>,
+}
+
+impl RegistryDiff {
+    pub fn between(old: &SetRegistry, new: &SetRegistry) -> RegistryDiff {
+        let mut keys: Vec<&String> = old.keys().chain(new.keys()).collect();
+        keys.sort();
+        keys.dedup();
+
+        let keys_changed = keys
+            .into_iter()
+            .filter_map(|key| {
+                let old_members = old.members(key);
+                let new_members = new.members(key);
+                let change = KeyChange {
+                    key: key.clone(),
+                    members_added: sorted_difference(&new_members, &old_members),
+                    members_removed: sorted_difference(&old_members, &new_members),
+                };
+                (!change.members_added.is_empty() || !change.members_removed.is_empty())
+                    .then_some(change)
+            })
+            .collect();
+
+        let old_ids: HashSet<&str> = old.ids().collect();
+        let new_ids: HashSet<&str> = new.ids().collect();
+
+        RegistryDiff {
+            ids_added: sorted_difference(&new_ids, &old_ids),
+            ids_removed: sorted_difference(&old_ids, &new_ids),
+            keys_changed,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ids_added.is_empty() && self.ids_removed.is_empty() && self.keys_changed.is_empty()
+    }
+}
+
+impl fmt::Display for RegistryDiff {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.is_empty() {
+            return writeln!(f, "No membership changes");
+        }
+        for id in &self.ids_added {
+            writeln!(f, "+ {}", id)?;
+        }
+        for id in &self.ids_removed {
+            writeln!(f, "- {}", id)?;
+        }
+        for change in &self.keys_changed {
+            writeln!(f, "{}", change.key)?;
+            for id in &change.members_added {
+                writeln!(f, "  + {}", id)?;
+            }
+            for id in &change.members_removed {
+                writeln!(f, "  - {}", id)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+// which registry a refresh replaces
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum RegistryKind {
+    Asset,
+    User,
+}
+
+// a refresh that has been evaluated but not applied
+// commit it with Database::commit_refresh once the impact has been reviewed
+#[derive(Debug, Clone)]
+pub struct RefreshPlan {
+    pub kind: RegistryKind,
+    pub registry: SetRegistry,
+    pub diff: RegistryDiff,
+    // statements whose atomic permissions change, in the order they were applied
+    pub impacts: Vec<StatementImpact>,
+    // net change to the effective permissions, after REVOKE, DENY and implications
+    pub effective_gained: Vec<AtomicPermission>,
+    pub effective_lost: Vec<AtomicPermission>,
+    // state the plan was computed against, a plan is stale once any of them moves
+    planned_sequence: u64,
+    planned_etl_datetime: DateTime<Utc>,
+    planned_registry_version: u64,
+    // sets of the statements re-resolved against the new registry, and the ids they gained or lost
+    resolved: Vec<(String, HashSet<String>)>,
+    changed_ids: HashSet<String>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct StatementImpact {
+    pub statement_id: String,
+    pub command_raw: String,
+    pub gained: Vec<AtomicPermission>,
+    pub lost: Vec<AtomicPermission>,
+}
+
+impl fmt::Display for RefreshPlan {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.diff)?;
+
+        for impact in &self.impacts {
+            writeln!(f)?;
+            writeln!(f, "{}: {}", impact.statement_id, impact.command_raw)?;
+            for atomic in &impact.gained {
+                writeln!(f, "  + {}", atomic)?;
+            }
+            for atomic in &impact.lost {
+                writeln!(f, "  - {}", atomic)?;
+            }
+        }
+
+        writeln!(f)?;
+        writeln!(
+            f,
+            "Effective permissions gained: {}, lost: {}",
+            self.effective_gained.len(),
+            self.effective_lost.len()
+        )
+    }
+}
+
+impl Database {
+    pub fn refresh_asset_registry(&mut self, csv_filepath: &Path) -> Result<RegistryDiff> {
+        let plan = self.plan_registry_refresh(RegistryKind::Asset, csv_filepath)?;
+        self.commit_refresh(plan)
+    }
+
+    pub fn refresh_user_registry(&mut self, csv_filepath: &Path) -> Result<RegistryDiff> {
+        let plan = self.plan_registry_refresh(RegistryKind::User, csv_filepath)?;
+        self.commit_refresh(plan)
+    }
+
+    pub fn replace_asset_registry(&mut self, registry: SetRegistry) -> RegistryDiff {
+        let plan = self.plan_refresh(RegistryKind::Asset, registry);
+        self.apply_refresh(plan)
+    }
+
+    pub fn replace_user_registry(&mut self, registry: SetRegistry) -> RegistryDiff {
+        let plan = self.plan_refresh(RegistryKind::User, registry);
+        self.apply_refresh(plan)
+    }
+
+    pub fn plan_registry_refresh(
+        &self,
+        kind: RegistryKind,
+        csv_filepath: &Path,
+    ) -> Result<RefreshPlan> {
+        let registry = load_set_registry_from_csv(csv_filepath)?;
+        Ok(self.plan_refresh(kind, registry))
+    }
+
+    // only statements referencing a changed key, or using ALL / NOT when ids come and go,
+    // are re-resolved against the new registry, and only the ids they gain or lose are evaluated
+    // the registry keeps its etl_datetime, which records when it was extracted
+    pub fn plan_refresh(&self, kind: RegistryKind, registry: SetRegistry) -> RefreshPlan {
+        let current = self.registry(kind);
+        let diff = RegistryDiff::between(current, &registry);
+
+        let mut cache = SetCache::new();
+        let mut refreshed: HashMap<String, Permission> = HashMap::new();
+        for statement_id in self.statements_depending_on(kind, &diff) {
+            let Some(statement) = self.statement_log.get(&statement_id) else {
+                continue;
+            };
+            let mut statement = statement.clone();
+            match kind {
+                RegistryKind::Asset => {
+                    statement.asset_set_affected = cache
+                        .evaluate(&statement.asset_set_expr, &registry)
+                        .to_names()
+                }
+                RegistryKind::User => {
+                    statement.user_set_affected = cache
+                        .evaluate(&statement.user_set_expr, &registry)
+                        .to_names()
+                }
+            }
+            refreshed.insert(statement_id, statement);
+        }
+
+        let before = self.ordered_statements();
+        let after: Vec<&Permission> = before
+            .iter()
+            .map(|statement| refreshed.get(&statement.statement_id).unwrap_or(statement))
+            .collect();
+
+        // an id in both sets keeps the same atomic permissions, as only one side was re-resolved
+        let mut impacts = Vec::new();
+        let mut changed_ids = BTreeSet::new();
+        for (before, after) in before.iter().zip(&after) {
+            if !refreshed.contains_key(&before.statement_id) {
+                continue;
+            }
+            let (before_ids, after_ids) = match kind {
+                RegistryKind::Asset => (&before.asset_set_affected, &after.asset_set_affected),
+                RegistryKind::User => (&before.user_set_affected, &after.user_set_affected),
+            };
+            let gained_ids: Vec<&String> = after_ids.difference(before_ids).collect();
+            let lost_ids: Vec<&String> = before_ids.difference(after_ids).collect();
+            if gained_ids.is_empty() && lost_ids.is_empty() {
+                continue;
+            }
+
+            let mut gained: Vec<AtomicPermission> = gained_ids
+                .iter()
+                .flat_map(|id| after.atomic_permissions_in(matrix_scope(kind, id)))
+                .collect();
+            let mut lost: Vec<AtomicPermission> = lost_ids
+                .iter()
+                .flat_map(|id| before.atomic_permissions_in(matrix_scope(kind, id)))
+                .collect();
+            gained.sort();
+            lost.sort();
+            changed_ids.extend(gained_ids.into_iter().chain(lost_ids));
+
+            impacts.push(StatementImpact {
+                statement_id: before.statement_id.clone(),
+                command_raw: before.command_raw.clone(),
+                gained,
+                lost,
+            });
+        }
+
+        // effective permissions can only change for the ids some statement gained or lost
+        let now = Utc::now();
+        let changed_ids: HashSet<String> = changed_ids.into_iter().cloned().collect();
+        let scope = matrix_scope_of_ids(kind, &changed_ids);
+        let effective_before = self.effective_permissions_of(&before, now, scope);
+        let effective_after = self.effective_permissions_of(&after, now, scope);
+        let effective_gained = sorted_difference(&effective_after, &effective_before);
+        let effective_lost = sorted_difference(&effective_before, &effective_after);
+
+        RefreshPlan {
+            kind,
+            diff,
+            impacts,
+            effective_gained,
+            effective_lost,
+            planned_sequence: self.last_sequence(),
+            planned_etl_datetime: current.etl_datetime,
+            planned_registry_version: current.version(),
+            resolved: refreshed
+                .into_iter()
+                .map(|(statement_id, statement)| match kind {
+                    RegistryKind::Asset => (statement_id, statement.asset_set_affected),
+                    RegistryKind::User => (statement_id, statement.user_set_affected),
+                })
+                .collect(),
+            changed_ids,
+            registry,
+        }
+    }
+
+    // the previous registry is kept in the history for point-in-time queries
+    // stored statements are re-resolved so access follows the new membership
+    // the plan is stale once a statement, refresh or membership delta has been applied since
+    pub fn commit_refresh(&mut self, plan: RefreshPlan) -> Result<RegistryDiff> {
+        self.check_refresh_plan(&plan)?;
+        Ok(self.apply_refresh(plan))
+    }
+
+    pub(crate) fn check_refresh_plan(&self, plan: &RefreshPlan) -> Result<()> {
+        let current = self.registry(plan.kind);
+        if plan.planned_sequence != self.last_sequence()
+            || plan.planned_etl_datetime != current.etl_datetime
+            || plan.planned_registry_version != current.version()
+        {
+            return Err(RhubarbError::StaleRefreshPlan);
+        }
+        Ok(())
+    }
+
+    // the sets re-resolved while planning are stored, and only the rows or columns of the ids
+    // some statement gained or lost are recomputed, all of them in a single pass over the log
+    pub(crate) fn apply_refresh(&mut self, plan: RefreshPlan) -> RegistryDiff {
+        let kind = plan.kind;
+        let previous = std::mem::replace(self.registry_mut(kind), plan.registry);
+        self.registry_history_mut(kind)
+            .push(RegistryChange::Refresh(previous));
+
+        for (statement_id, set_affected) in plan.resolved {
+            if let Some(statement) = self.statement_log.get_mut(&statement_id) {
+                match kind {
+                    RegistryKind::Asset => statement.asset_set_affected = set_affected,
+                    RegistryKind::User => statement.user_set_affected = set_affected,
+                }
+            }
+        }
+        self.record_registry_load(kind);
+
+        self.recompute_access_matrix(matrix_scope_of_ids(kind, &plan.changed_ids));
+
+        plan.diff
+    }
+
+    // adds a single membership e.g. an employee joining a department
+    // only the statements depending on the key are re-resolved and only the row or column
+    // of the id is recomputed, returning the ids of the statements that were re-resolved
+    // the delta is recorded in the history and undone by point-in-time queries
+    pub fn insert_membership(&mut self, kind: RegistryKind, key: &str, id: &str) -> Vec<String> {
+        self.update_membership(kind, key, id, true, Utc::now())
+    }
+
+    pub fn delete_membership(&mut self, kind: RegistryKind, key: &str, id: &str) -> Vec<String> {
+        self.update_membership(kind, key, id, false, Utc::now())
+    }
+
+    // the registry is stamped with the time of the delta, replaying a delta passes the logged time
+    pub(crate) fn update_membership(
+        &mut self,
+        kind: RegistryKind,
+        key: &str,
+        id: &str,
+        insert: bool,
+        etl_datetime: DateTime<Utc>,
+    ) -> Vec<String> {
+        let registry = self.registry_mut(kind);
+        if registry.is_member(key, id) == insert {
+            return Vec::new();
+        }
+
+        let previous_etl_datetime = registry.etl_datetime;
+        let new_id = insert && registry.insert_id(id);
+        if insert {
+            registry.insert(key.to_string(), id.to_string());
+        } else {
+            registry.delete(key, id);
+        }
+        registry.etl_datetime = etl_datetime;
+        self.registry_history_mut(kind)
+            .push(RegistryChange::Membership {
+                key: key.to_string(),
+                id: id.to_string(),
+                insert,
+                new_id,
+                previous_etl_datetime,
+            });
+
+        let (key_dependencies, id_dependencies) = match kind {
+            RegistryKind::Asset => (&self.dependencies.asset_keys, &self.dependencies.asset_ids),
+            RegistryKind::User => (&self.dependencies.user_keys, &self.dependencies.user_ids),
+        };
+        let mut affected: Vec<String> = key_dependencies
+            .get(key)
+            .into_iter()
+            .flatten()
+            .chain(id_dependencies.iter().filter(|_| new_id))
+            .cloned()
+            .collect();
+        affected.sort();
+        affected.dedup();
+
+        for statement_id in &affected {
+            let Some(statement) = self.statement_log.get_mut(statement_id) else {
+                continue;
+            };
+            let (set_expr, set_affected, registry) = match kind {
+                RegistryKind::Asset => (
+                    &statement.asset_set_expr,
+                    &mut statement.asset_set_affected,
+                    &self.asset_registry,
+                ),
+                RegistryKind::User => (
+                    &statement.user_set_expr,
+                    &mut statement.user_set_affected,
+                    &self.user_registry,
+                ),
+            };
+
+            if parse_set::set_contains(set_expr, id, registry) {
+                set_affected.insert(id.to_string());
+            } else {
+                set_affected.remove(id);
+            }
+        }
+
+        self.record_registry_load(kind);
+        self.recompute_access_matrix(matrix_scope(kind, id));
+
+        affected
+    }
+
+    // statements referencing a changed key, or using ALL / NOT when ids were added or removed
+    fn statements_depending_on(&self, kind: RegistryKind, diff: &RegistryDiff) -> Vec<String> {
+        let (key_dependencies, id_dependencies) = match kind {
+            RegistryKind::Asset => (&self.dependencies.asset_keys, &self.dependencies.asset_ids),
+            RegistryKind::User => (&self.dependencies.user_keys, &self.dependencies.user_ids),
+        };
+        let ids_changed = !diff.ids_added.is_empty() || !diff.ids_removed.is_empty();
+
+        let mut affected: Vec<String> = diff
+            .keys_changed
+            .iter()
+            .filter_map(|change| key_dependencies.get(&change.key))
+            .flatten()
+            .chain(id_dependencies.iter().filter(|_| ids_changed))
+            .cloned()
+            .collect();
+        affected.sort();
+        affected.dedup();
+        affected
+    }
+
+    pub(crate) fn registry(&self, kind: RegistryKind) -> &SetRegistry {
+        match kind {
+            RegistryKind::Asset => &self.asset_registry,
+            RegistryKind::User => &self.user_registry,
+        }
+    }
+
+    fn registry_mut(&mut self, kind: RegistryKind) -> &mut SetRegistry {
+        match kind {
+            RegistryKind::Asset => &mut self.asset_registry,
+            RegistryKind::User => &mut self.user_registry,
+        }
+    }
+
+    fn registry_history_mut(&mut self, kind: RegistryKind) -> &mut Vec<RegistryChange> {
+        match kind {
+            RegistryKind::Asset => &mut self.asset_registry_history,
+            RegistryKind::User => &mut self.user_registry_history,
+        }
+    }
+
+    // every stored set now reflects the current registry, whether or not it was re-resolved
+    fn record_registry_load(&mut self, kind: RegistryKind) {
+        let etl_datetime = self.registry(kind).etl_datetime;
+        for statement in self.statement_log.values_mut() {
+            match kind {
+                RegistryKind::Asset => {
+                    statement.metadata.last_resolved_asset_etl_datetime = Some(etl_datetime)
+                }
+                RegistryKind::User => {
+                    statement.metadata.last_resolved_user_etl_datetime = Some(etl_datetime)
+                }
+            }
+        }
+    }
+}
+
+// the row or column of the access matrix holding the id
+fn matrix_scope(kind: RegistryKind, id: &str) -> MatrixScope<'_> {
+    match kind {
+        RegistryKind::Asset => MatrixScope::Asset(id),
+        RegistryKind::User => MatrixScope::User(id),
+    }
+}
+
+// the rows or columns of the access matrix holding the ids
+fn matrix_scope_of_ids(kind: RegistryKind, ids: &HashSet<String>) -> MatrixScope<'_> {
+    match kind {
+        RegistryKind::Asset => MatrixScope::Assets(ids),
+        RegistryKind::User => MatrixScope::Users(ids),
+    }
+}
+
+fn sorted_difference<T, U>(left: &HashSet<T>, right: &HashSet<T>) -> Vec<U>
+where
+    T: Clone + Ord + std::hash::Hash + Into<U>,
+{
+    let mut difference: Vec<&T> = left.difference(right).collect();
+    difference.sort();
+    difference.into_iter().cloned().map(Into::into).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        history::AsOf,
+        test_support::{datetime, mock_database, temp_path},
+    };
+    use std::collections::HashMap;
+
+    fn registry(entries: &[(&str, &[&str])]) -> SetRegistry {
+        let data: HashMap<String, HashSet<String>> = entries
+            .iter()
+            .map(|(key, ids)| {
+                (
+                    key.to_string(),
+                    ids.iter().map(|id| id.to_string()).collect(),
+                )
+            })
+            .collect();
+        SetRegistry::from(data)
+    }
+
+    // incremental updates must leave the same access as re-resolving and rebuilding everything
+    fn assert_matches_full_rebuild(database: &Database) {
+        let mut rebuilt = database.clone();
+        rebuilt.resolve_statement_sets();
+        rebuilt.rebuild_access_matrix();
+        assert_eq!(
+            database.export_access(Utc::now()),
+            rebuilt.export_access(Utc::now())
+        );
+    }
+
+    #[test]
+    fn test_registry_diff() {
+        // arrange
+        let old = registry(&[
+            ("department:tax", &["alice", "bob"]),
+            ("department:audit", &["carol"]),
+        ]);
+        let new = registry(&[
+            ("department:tax", &["alice"]),
+            ("department:audit", &["bob", "carol"]),
+            ("department:legal", &["dave"]),
+        ]);
+
+        // act
+        let diff = RegistryDiff::between(&old, &new);
+
+        // assert
+        assert_eq!(diff.ids_added, vec!["dave"]);
+        assert!(diff.ids_removed.is_empty());
+        assert_eq!(
+            diff.keys_changed,
+            vec![
+                KeyChange {
+                    key: "department:audit".to_string(),
+                    members_added: vec!["bob".to_string()],
+                    members_removed: vec![],
+                },
+                KeyChange {
+                    key: "department:legal".to_string(),
+                    members_added: vec!["dave".to_string()],
+                    members_removed: vec![],
+                },
+                KeyChange {
+                    key: "department:tax".to_string(),
+                    members_added: vec![],
+                    members_removed: vec!["bob".to_string()],
+                },
+            ]
+        );
+        assert!(RegistryDiff::between(&new, &new).is_empty());
+    }
+
+    #[test]
+    fn test_refresh_user_registry() {
+        // arrange
+        let mut database = mock_database();
+        database
+            .apply_command("GRANT READ ON table:items TO department:tax")
+            .unwrap();
+        let previous_etl_datetime = database.user_registry.etl_datetime;
+
+        // laura moves from tax to audit, every other row is unchanged
+        let contents = std::fs::read_to_string("mock_data/employees.csv").unwrap();
+        let (header, rows) = contents.split_once('\n').unwrap();
+        let moved: Vec<String> = rows
+            .lines()
+            .map(|row| {
+                if row.starts_with("laura.martinez@example.com,") {
+                    row.replacen(",Tax,", ",Audit,", 1)
+                } else {
+                    row.to_string()
+                }
+            })
+            .collect();
+        let path = temp_path("refresh_users.csv");
+        std::fs::write(&path, format!("{}\n{}\n", header, moved.join("\n"))).unwrap();
+
+        // act
+        let diff = database.refresh_user_registry(&path).unwrap();
+
+        // assert
+        let user_id = "laura.martinez@example.com".to_string();
+        assert!(diff.ids_added.is_empty());
+        assert!(diff.ids_removed.is_empty());
+        assert!(diff.keys_changed.iter().any(|change| {
+            change.key == "department:tax" && change.members_removed == vec![user_id.clone()]
+        }));
+        assert!(database.user_registry.etl_datetime > previous_etl_datetime);
+        assert_eq!(database.user_registry_history.len(), 1);
+        let metadata = &database.ordered_statements()[0].metadata;
+        assert_eq!(metadata.user_registry_etl_datetime, previous_etl_datetime);
+        assert_eq!(
+            metadata.last_resolved_user_etl_datetime,
+            Some(database.user_registry.etl_datetime)
+        );
+        assert_eq!(metadata.last_resolved_asset_etl_datetime, None);
+        assert!(!database.check(&user_id, "inventory.items", "READ"));
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_plan_refresh_impacts() {
+        // arrange
+        let mut database = mock_database();
+        let grant_id = database
+            .apply_command("GRANT READ ON table:items TO department:tax")
+            .unwrap();
+        let deny_id = database
+            .apply_command("DENY READ ON table:items TO designation:partner")
+            .unwrap();
+        let user_id = "laura.martinez@example.com".to_string();
+
+        let mut registry = database.user_registry.clone();
+        registry.delete("department:tax", &user_id);
+        registry.delete("designation:partner", &user_id);
+        registry.insert("designation:senior".to_string(), user_id.clone());
+        registry.etl_datetime = datetime("2026-04-01T00:00:00Z");
+
+        // act
+        let plan = database.plan_refresh(RegistryKind::User, registry);
+
+        // assert - laura leaves both the grant and the deny, so her effective access is unchanged
+        let atomic = AtomicPermission {
+            user_id: user_id.clone(),
+            asset_id: "inventory.items".to_string(),
+            permission: "READ".to_string(),
+        };
+        assert_eq!(
+            plan.impacts,
+            vec![
+                StatementImpact {
+                    statement_id: grant_id,
+                    command_raw: "GRANT READ ON table:items TO department:tax".to_string(),
+                    gained: vec![],
+                    lost: vec![atomic.clone()],
+                },
+                StatementImpact {
+                    statement_id: deny_id,
+                    command_raw: "DENY READ ON table:items TO designation:partner".to_string(),
+                    gained: vec![],
+                    lost: vec![atomic],
+                },
+            ]
+        );
+        assert!(plan.effective_gained.is_empty());
+        assert!(plan.effective_lost.is_empty());
+        // nothing changes until the plan is committed
+        assert!(database.user_registry.is_member("department:tax", &user_id));
+
+        database.commit_refresh(plan).unwrap();
+        assert!(!database.user_registry.is_member("department:tax", &user_id));
+        // the extraction time of the refreshed registry is kept
+        assert_eq!(
+            database.user_registry.etl_datetime,
+            datetime("2026-04-01T00:00:00Z")
+        );
+    }
+
+    #[test]
+    fn test_plan_refresh_effective_change() {
+        let mut database = mock_database();
+        database
+            .apply_command("GRANT READ ON table:items TO department:tax")
+            .unwrap();
+        let user_id = "laura.martinez@example.com".to_string();
+
+        let mut registry = database.user_registry.clone();
+        registry.delete("department:tax", &user_id);
+        let plan = database.plan_refresh(RegistryKind::User, registry);
+
+        assert!(plan.effective_gained.is_empty());
+        assert_eq!(
+            plan.effective_lost,
+            vec![AtomicPermission {
+                user_id,
+                asset_id: "inventory.items".to_string(),
+                permission: "READ".to_string(),
+            }]
+        );
+    }
+
+    #[test]
+    fn test_plan_refresh_matches_committed_change() {
+        // arrange
+        let mut database = mock_database();
+        database
+            .apply_command("GRANT READ ON schema:inventory TO department:tax OR designation:senior")
+            .unwrap();
+        database
+            .apply_command("DENY WRITE ON ALL TO NOT designation:partner")
+            .unwrap();
+        database
+            .apply_command("GRANT ADMIN ON table:items TO designation:partner")
+            .unwrap();
+        let user_id = "laura.martinez@example.com".to_string();
+
+        let mut registry = database.user_registry.clone();
+        registry.delete("designation:partner", &user_id);
+        registry.insert("designation:senior".to_string(), user_id);
+        registry.insert_id("new.joiner");
+        registry.insert("department:tax".to_string(), "new.joiner".to_string());
+        let effective_before = database.effective_permissions();
+
+        // act
+        let plan = database.plan_refresh(RegistryKind::User, registry);
+        let (gained, lost) = (plan.effective_gained.clone(), plan.effective_lost.clone());
+        database.commit_refresh(plan).unwrap();
+
+        // assert - only the changed rows were recomputed, which must match a full rebuild
+        assert_matches_full_rebuild(&database);
+        let effective_after = database.effective_permissions();
+        assert!(!gained.is_empty());
+        assert!(!lost.is_empty());
+        assert_eq!(
+            gained,
+            sorted_difference::<_, AtomicPermission>(&effective_after, &effective_before)
+        );
+        assert_eq!(
+            lost,
+            sorted_difference::<_, AtomicPermission>(&effective_before, &effective_after)
+        );
+    }
+
+    #[test]
+    fn test_commit_stale_refresh_plan() {
+        let mut database = mock_database();
+        let plan = database.plan_refresh(RegistryKind::User, database.user_registry.clone());
+
+        database
+            .apply_command("GRANT READ ON table:items TO department:tax")
+            .unwrap();
+
+        assert_eq!(
+            database.commit_refresh(plan),
+            Err(RhubarbError::StaleRefreshPlan)
+        );
+        assert!(database.user_registry_history.is_empty());
+    }
+
+    #[test]
+    fn test_commit_refresh_plan_stale_after_membership_delta() {
+        // arrange
+        let mut database = mock_database();
+        database
+            .apply_command("GRANT READ ON table:items TO department:tax")
+            .unwrap();
+        let plan = database.plan_refresh(RegistryKind::User, database.user_registry.clone());
+
+        // act - the delta moves neither the sequence nor the plan's registry
+        database.delete_membership(
+            RegistryKind::User,
+            "department:tax",
+            "laura.martinez@example.com",
+        );
+        let result = database.commit_refresh(plan);
+
+        // assert
+        assert_eq!(result, Err(RhubarbError::StaleRefreshPlan));
+        assert!(!database
+            .user_registry
+            .is_member("department:tax", "laura.martinez@example.com"));
+    }
+
+    #[test]
+    fn test_membership_delta_matches_full_rebuild() {
+        // arrange
+        let mut database = mock_database();
+        let grant_id = database
+            .apply_command("GRANT READ ON table:items TO department:tax")
+            .unwrap();
+        let deny_id = database
+            .apply_command("DENY READ ON ALL TO NOT designation:partner")
+            .unwrap();
+        database
+            .apply_command("GRANT WRITE ON schema:inventory TO designation:senior")
+            .unwrap();
+        let user_id = "laura.martinez@example.com";
+
+        // act
+        let moved = database.delete_membership(RegistryKind::User, "department:tax", user_id);
+        let joined = database.insert_membership(RegistryKind::User, "department:tax", "new.joiner");
+        let unchanged = database.delete_membership(RegistryKind::User, "department:tax", user_id);
+
+        // assert - only statements referencing the key, or ALL / NOT for a new id, are touched
+        assert_eq!(moved, vec![grant_id.clone()]);
+        let mut expected = vec![grant_id, deny_id];
+        expected.sort();
+        assert_eq!(joined, expected);
+        assert!(unchanged.is_empty());
+
+        assert!(!database.check(user_id, "inventory.items", "READ"));
+        assert_matches_full_rebuild(&database);
+    }
+
+    #[test]
+    fn test_membership_delta_keeps_history() {
+        // arrange
+        let mut database = mock_database();
+        database
+            .apply_command("GRANT READ ON table:items TO department:tax")
+            .unwrap();
+        let user_id = "laura.martinez@example.com";
+
+        // act
+        database.delete_membership(RegistryKind::User, "department:tax", user_id);
+
+        // assert - access as of the grant still reflects the membership at the time
+        assert!(!database.check(user_id, "inventory.items", "READ"));
+        assert!(database
+            .check_as_of(user_id, "inventory.items", "READ", AsOf::Sequence(1))
+            .unwrap());
+        assert_eq!(database.user_registry_history.len(), 1);
+        assert!(matches!(
+            &database.user_registry_history[0],
+            RegistryChange::Membership { key, id, insert: false, .. }
+                if key == "department:tax" && id == user_id
+        ));
+    }
+
+    #[test]
+    fn test_asset_membership_delta() {
+        let mut database = mock_database();
+        database
+            .apply_command("GRANT READ ON table:items TO department:tax")
+            .unwrap();
+
+        database.insert_membership(RegistryKind::Asset, "table:items", "inventory.shipments");
+
+        assert!(database.check("laura.martinez@example.com", "inventory.shipments", "READ"));
+        assert_matches_full_rebuild(&database);
+    }
+}
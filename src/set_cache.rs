@@ -0,0 +1,356 @@
+use std::{borrow::Cow, collections::HashMap};
+
+use rayon::prelude::*;
+use roaring::RoaringBitmap;
+
+use crate::{
+    database::SetRegistry,
+    parse_set::{self, ResolvedSet, SetExpr},
+};
+
+// memoised sub-expressions evaluated against one version of a registry
+// statements often share fragments such as (designation:partner OR designation:senior)
+// so re-resolving every statement after a refresh evaluates each fragment once
+// the cache empties itself when used with a different registry or a modified one
+#[derive(Debug, Clone, Default)]
+pub struct SetCache {
+    registry_version: Option<u64>,
+    // keyed by canonical hash, the expression is kept to rule out hash collisions
+    entries: HashMap<u64, Vec<(SetExpr, RoaringBitmap)>>,
+    hits: usize,
+}
+
+impl SetCache {
+    pub fn new() -> SetCache {
+        SetCache::default()
+    }
+
+    // as parse_set::evaluate, keys missing from the registry match nothing
+    pub fn evaluate<'r>(
+        &mut self,
+        parsed_expression: &SetExpr,
+        registry: &'r SetRegistry,
+    ) -> ResolvedSet<'r> {
+        self.use_registry(registry);
+
+        let handles = self.evaluate_handles(parsed_expression, registry);
+        ResolvedSet::from_handles(registry, handles)
+    }
+
+    // as evaluate for each expression, with every distinct sub-expression still evaluated once
+    // sub-expressions are evaluated concurrently, innermost first so their operands are cached
+    pub fn evaluate_all<'r>(
+        &mut self,
+        parsed_expressions: &[&SetExpr],
+        registry: &'r SetRegistry,
+    ) -> Vec<ResolvedSet<'r>> {
+        self.use_registry(registry);
+
+        // levels[0] only has leaves or cached sub-expressions as operands, levels[1] adds levels[0]...
+        let mut levels: Vec<Vec<&SetExpr>> = Vec::new();
+        let mut pending: HashMap<u64, Vec<(&SetExpr, usize)>> = HashMap::new();
+        for parsed_expression in parsed_expressions {
+            self.collect_pending(parsed_expression, &mut pending, &mut levels);
+        }
+
+        for level in levels {
+            let evaluated: Vec<RoaringBitmap> = level
+                .par_iter()
+                .map(|parsed_expression| self.evaluate_operator(parsed_expression, registry))
+                .collect();
+            for (parsed_expression, handles) in level.into_iter().zip(evaluated) {
+                self.entries
+                    .entry(parsed_expression.canonical_hash())
+                    .or_default()
+                    .push((parsed_expression.clone(), handles));
+            }
+        }
+
+        parsed_expressions
+            .iter()
+            .map(|parsed_expression| {
+                ResolvedSet::from_handles(registry, self.cached(parsed_expression, registry))
+            })
+            .collect()
+    }
+
+    // number of memoised sub-expressions
+    pub fn len(&self) -> usize {
+        self.entries.values().map(Vec::len).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    // number of sub-expressions answered from the cache
+    pub fn hits(&self) -> usize {
+        self.hits
+    }
+
+    fn use_registry(&mut self, registry: &SetRegistry) {
+        if self.registry_version != Some(registry.version()) {
+            self.entries.clear();
+            self.registry_version = Some(registry.version());
+        }
+    }
+
+    fn lookup(&self, parsed_expression: &SetExpr) -> Option<&RoaringBitmap> {
+        self.entries
+            .get(&parsed_expression.canonical_hash())
+            .into_iter()
+            .flatten()
+            .find(|(expr, _)| expr.canonical_eq(parsed_expression))
+            .map(|(_, handles)| handles)
+    }
+
+    // leaves are borrowed from the registry, anything else must already be cached
+    fn cached<'r>(
+        &self,
+        parsed_expression: &SetExpr,
+        registry: &'r SetRegistry,
+    ) -> Cow<'r, RoaringBitmap> {
+        if parsed_expression.is_leaf() {
+            return parse_set::evaluate(parsed_expression, registry).into_handles();
+        }
+        let handles = self
+            .lookup(parsed_expression)
+            .expect("operands are evaluated before the operators using them");
+        Cow::Owned(handles.clone())
+    }
+
+    // queues each distinct uncached operator at the level above its highest queued operand
+    // a repeated or cached operator counts as a hit, as it would in evaluate
+    // returns the level above the expression, 0 for leaves and cached operators
+    fn collect_pending<'e>(
+        &mut self,
+        parsed_expression: &'e SetExpr,
+        pending: &mut HashMap<u64, Vec<(&'e SetExpr, usize)>>,
+        levels: &mut Vec<Vec<&'e SetExpr>>,
+    ) -> usize {
+        if parsed_expression.is_leaf() {
+            return 0;
+        }
+        if self.lookup(parsed_expression).is_some() {
+            self.hits += 1;
+            return 0;
+        }
+
+        let hash = parsed_expression.canonical_hash();
+        if let Some((_, level)) = pending
+            .get(&hash)
+            .into_iter()
+            .flatten()
+            .find(|(expr, _)| expr.canonical_eq(parsed_expression))
+        {
+            self.hits += 1;
+            return level + 1;
+        }
+
+        let level = match parsed_expression {
+            SetExpr::Union(left, right)
+            | SetExpr::Intersection(left, right)
+            | SetExpr::Except(left, right) => {
+                let left = self.collect_pending(left, pending, levels);
+                let right = self.collect_pending(right, pending, levels);
+                left.max(right)
+            }
+            SetExpr::Not(inner) => self.collect_pending(inner, pending, levels),
+            SetExpr::Set(_) | SetExpr::Universe => unreachable!("leaves are returned above"),
+        };
+
+        if levels.len() <= level {
+            levels.resize_with(level + 1, Vec::new);
+        }
+        levels[level].push(parsed_expression);
+        pending
+            .entry(hash)
+            .or_default()
+            .push((parsed_expression, level));
+        level + 1
+    }
+
+    // a single operator whose operands are leaves or already cached
+    fn evaluate_operator(
+        &self,
+        parsed_expression: &SetExpr,
+        registry: &SetRegistry,
+    ) -> RoaringBitmap {
+        let operand = |operand: &SetExpr| self.cached(operand, registry);
+        match parsed_expression {
+            SetExpr::Union(left, right) => operand(left).as_ref() | operand(right).as_ref(),
+            SetExpr::Intersection(left, right) => operand(left).as_ref() & operand(right).as_ref(),
+            SetExpr::Except(left, right) => operand(left).as_ref() - operand(right).as_ref(),
+            SetExpr::Not(inner) => registry.id_bitmap() - operand(inner).as_ref(),
+            SetExpr::Set(_) | SetExpr::Universe => unreachable!("only operators are queued"),
+        }
+    }
+
+    fn evaluate_handles<'r>(
+        &mut self,
+        parsed_expression: &SetExpr,
+        registry: &'r SetRegistry,
+    ) -> Cow<'r, RoaringBitmap> {
+        // single keys and ALL are borrowed from the registry and not worth caching
+        if parsed_expression.is_leaf() {
+            return parse_set::evaluate(parsed_expression, registry).into_handles();
+        }
+
+        if let Some(handles) = self.lookup(parsed_expression) {
+            let handles = handles.clone();
+            self.hits += 1;
+            return Cow::Owned(handles);
+        }
+
+        let handles = match parsed_expression {
+            SetExpr::Union(left, right) => {
+                self.evaluate_handles(left, registry).as_ref()
+                    | self.evaluate_handles(right, registry).as_ref()
+            }
+            SetExpr::Intersection(left, right) => {
+                self.evaluate_handles(left, registry).as_ref()
+                    & self.evaluate_handles(right, registry).as_ref()
+            }
+            SetExpr::Except(left, right) => {
+                self.evaluate_handles(left, registry).as_ref()
+                    - self.evaluate_handles(right, registry).as_ref()
+            }
+            SetExpr::Not(inner) => {
+                registry.id_bitmap() - self.evaluate_handles(inner, registry).as_ref()
+            }
+            SetExpr::Set(_) | SetExpr::Universe => unreachable!("leaves are returned above"),
+        };
+
+        self.entries
+            .entry(parsed_expression.canonical_hash())
+            .or_default()
+            .push((parsed_expression.clone(), handles.clone()));
+        Cow::Owned(handles)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::mock_database;
+    use std::collections::{HashMap, HashSet};
+
+    fn registry() -> SetRegistry {
+        let mut data = HashMap::new();
+        for (key, ids) in [
+            ("designation:partner", "alice bob"),
+            ("designation:senior", "carol"),
+            ("department:tax", "alice carol dave"),
+            ("department:audit", "bob erin"),
+        ] {
+            let ids: HashSet<String> = ids.split_whitespace().map(|s| s.to_string()).collect();
+            data.insert(key.to_string(), ids);
+        }
+        SetRegistry::from(data)
+    }
+
+    #[test]
+    fn test_shared_fragments_are_evaluated_once() {
+        // arrange
+        let registry = registry();
+        let mut cache = SetCache::new();
+        let statements: Vec<SetExpr> = [
+            "(designation:partner OR designation:senior) AND department:tax",
+            "(designation:senior OR designation:partner) AND department:audit",
+            "department:tax AND (designation:partner OR designation:senior)",
+        ]
+        .iter()
+        .map(|input| parse_set::parse_set_expr(input).unwrap())
+        .collect();
+
+        // act
+        let resolved: Vec<_> = statements
+            .iter()
+            .map(|expr| cache.evaluate(expr, &registry).to_names())
+            .collect();
+
+        // assert
+        for (expr, names) in statements.iter().zip(&resolved) {
+            assert_eq!(*names, parse_set::resolve_stored_set(expr, &registry));
+        }
+        // the OR fragment and the two AND expressions, the third statement is a hit
+        assert_eq!(cache.len(), 3);
+        assert_eq!(cache.hits(), 2);
+    }
+
+    #[test]
+    fn test_evaluate_all_evaluates_shared_fragments_once() {
+        // arrange
+        let registry = registry();
+        let mut cache = SetCache::new();
+        let statements: Vec<SetExpr> = [
+            "(designation:partner OR designation:senior) AND department:tax",
+            "(designation:senior OR designation:partner) AND department:audit",
+            "department:tax AND (designation:partner OR designation:senior)",
+            "NOT ((designation:partner OR designation:senior) AND department:tax)",
+            "department:audit",
+        ]
+        .iter()
+        .map(|input| parse_set::parse_set_expr(input).unwrap())
+        .collect();
+        let exprs: Vec<&SetExpr> = statements.iter().collect();
+
+        // act
+        let resolved = cache.evaluate_all(&exprs, &registry);
+
+        // assert
+        for (expr, resolved) in statements.iter().zip(&resolved) {
+            assert_eq!(
+                resolved.to_names(),
+                parse_set::resolve_stored_set(expr, &registry)
+            );
+        }
+        // OR, the two ANDs and the NOT are evaluated once, the repeated OR and tax AND are hits
+        assert_eq!(cache.len(), 4);
+        assert_eq!(cache.hits(), 3);
+    }
+
+    #[test]
+    fn test_statement_log_resolution_shares_the_cache() {
+        // arrange
+        let mut database = mock_database();
+        for input in [
+            "GRANT READ ON schema:inventory TO (designation:partner OR designation:senior) AND department:tax",
+            "GRANT WRITE ON table:products TO department:tax AND (designation:senior OR designation:partner)",
+            "DENY READ ON table:items TO (designation:senior OR designation:partner) EXCEPT department:tax",
+        ] {
+            database.apply_command(input).unwrap();
+        }
+        let (mut asset_cache, mut user_cache) = (SetCache::new(), SetCache::new());
+
+        // act
+        database.resolve_statement_sets_with(&mut asset_cache, &mut user_cache);
+
+        // assert - the OR, AND and EXCEPT are evaluated once across the whole log
+        assert_eq!(user_cache.len(), 3);
+        assert_eq!(user_cache.hits(), 2);
+        assert!(asset_cache.is_empty());
+
+        // resolving again against the same registry answers every statement from the cache
+        database.resolve_statement_sets_with(&mut asset_cache, &mut user_cache);
+        assert_eq!(user_cache.len(), 3);
+        assert_eq!(user_cache.hits(), 5);
+    }
+
+    #[test]
+    fn test_cache_is_cleared_when_the_registry_changes() {
+        // arrange
+        let mut registry = registry();
+        let mut cache = SetCache::new();
+        let expr = parse_set::parse_set_expr("department:tax EXCEPT designation:senior").unwrap();
+        cache.evaluate(&expr, &registry);
+
+        // act
+        registry.delete("department:tax", "alice");
+        let resolved = cache.evaluate(&expr, &registry);
+
+        // assert
+        assert_eq!(resolved.iter().collect::<Vec<_>>(), vec!["dave"]);
+        assert_eq!(cache.hits(), 0);
+    }
+}
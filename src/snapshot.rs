@@ -0,0 +1,214 @@
+use std::{borrow::Cow, collections::HashMap, fs, path::Path};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    access_matrix::AccessMatrix,
+    database::{Database, Permission, SetRegistry, StatementDependencies},
+    error::{Result, RhubarbError},
+    history::RegistryChange,
+    permission_hierarchy::PermissionHierarchy,
+};
+
+// bumped whenever the snapshot layout changes
+pub const SNAPSHOT_VERSION: u32 = 1;
+
+// on-disk form of a database, borrowed when saving and owned when loading
+// the access matrix is derived from the statement log and rebuilt on load
+#[derive(Serialize, Deserialize)]
+struct DatabaseSnapshot<'a> {
+    version: u32,
+    asset_registry: Cow<'a, SetRegistry>,
+    user_registry: Cow<'a, SetRegistry>,
+    asset_registry_history: Cow<'a, [RegistryChange]>,
+    user_registry_history: Cow<'a, [RegistryChange]>,
+    valid_permissions: Cow<'a, [String]>,
+    permission_implications: Cow<'a, HashMap<String, Vec<String>>>,
+    // in the order they were applied
+    statements: Vec<Cow<'a, Permission>>,
+    // write-ahead log records up to this sequence are reflected in the snapshot
+    log_sequence: u64,
+}
+
+// read on its own first so an unsupported layout is reported as such
+#[derive(Deserialize)]
+struct SnapshotHeader {
+    version: u32,
+}
+
+impl Database {
+    // written to a temporary file and renamed so a failed save leaves the previous snapshot intact
+    pub fn save_snapshot(&self, path: &Path) -> Result<()> {
+        let snapshot = DatabaseSnapshot {
+            version: SNAPSHOT_VERSION,
+            asset_registry: Cow::Borrowed(&self.asset_registry),
+            user_registry: Cow::Borrowed(&self.user_registry),
+            asset_registry_history: Cow::Borrowed(&self.asset_registry_history),
+            user_registry_history: Cow::Borrowed(&self.user_registry_history),
+            valid_permissions: Cow::Borrowed(&self.valid_permissions),
+            permission_implications: Cow::Borrowed(self.permission_hierarchy.implications()),
+            statements: self
+                .ordered_statements()
+                .into_iter()
+                .map(Cow::Borrowed)
+                .collect(),
+            log_sequence: self.log_sequence,
+        };
+
+        let json = serde_json::to_string(&snapshot).map_err(|err| snapshot_error(path, err))?;
+
+        let temp_path = path.with_extension("tmp");
+        fs::write(&temp_path, json).map_err(|err| snapshot_error(path, err))?;
+        fs::rename(&temp_path, path).map_err(|err| snapshot_error(path, err))
+    }
+}
+
+pub fn new_database_from_snapshot(path: &Path) -> Result<Database> {
+    let json = fs::read_to_string(path).map_err(|err| snapshot_error(path, err))?;
+
+    let header: SnapshotHeader =
+        serde_json::from_str(&json).map_err(|err| snapshot_error(path, err))?;
+    if header.version != SNAPSHOT_VERSION {
+        return Err(snapshot_error(
+            path,
+            format!(
+                "Unsupported snapshot version {}, expected {}",
+                header.version, SNAPSHOT_VERSION
+            ),
+        ));
+    }
+
+    let snapshot: DatabaseSnapshot =
+        serde_json::from_str(&json).map_err(|err| snapshot_error(path, err))?;
+
+    let valid_permissions = snapshot.valid_permissions.into_owned();
+    let permission_hierarchy = PermissionHierarchy::new(
+        snapshot.permission_implications.into_owned(),
+        &valid_permissions,
+    )?;
+
+    let statement_log = snapshot
+        .statements
+        .into_iter()
+        .map(|statement| {
+            let statement = statement.into_owned();
+            (statement.statement_id.clone(), statement)
+        })
+        .collect();
+
+    let mut database = Database {
+        asset_registry: snapshot.asset_registry.into_owned(),
+        user_registry: snapshot.user_registry.into_owned(),
+        asset_registry_history: snapshot.asset_registry_history.into_owned(),
+        user_registry_history: snapshot.user_registry_history.into_owned(),
+        valid_permissions,
+        permission_hierarchy,
+        statement_log,
+        access_matrix: AccessMatrix::new(),
+        dependencies: StatementDependencies::default(),
+        log_sequence: snapshot.log_sequence,
+    };
+    database.rebuild_access_matrix();
+
+    Ok(database)
+}
+
+fn snapshot_error(path: &Path, err: impl ToString) -> RhubarbError {
+    RhubarbError::Snapshot {
+        path: path.to_path_buf(),
+        message: err.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        parse_command::Submission,
+        registry_refresh::RegistryKind,
+        test_support::{datetime, mock_database, temp_path},
+    };
+
+    #[test]
+    fn test_snapshot_round_trip() {
+        // arrange
+        let mut database = mock_database();
+        database
+            .apply_command_as(
+                "GRANT WRITE ON schema:inventory TO department:tax UNTIL 2999-12-31",
+                &Submission::new("carol").with_ticket("SEC-1"),
+            )
+            .unwrap();
+        database
+            .apply_command("DENY READ ON table:products TO designation:senior")
+            .unwrap();
+        database.update_membership(
+            RegistryKind::User,
+            "department:tax",
+            "new.starter@example.com",
+            true,
+            datetime("2026-01-01T00:00:00Z"),
+        );
+        database.log_sequence = 3;
+        let path = temp_path("round_trip.json");
+
+        // act
+        database.save_snapshot(&path).unwrap();
+        let loaded = new_database_from_snapshot(&path).unwrap();
+
+        // assert
+        assert_eq!(loaded.asset_registry, database.asset_registry);
+        assert_eq!(loaded.user_registry, database.user_registry);
+        assert_eq!(
+            loaded.asset_registry_history,
+            database.asset_registry_history
+        );
+        assert_eq!(loaded.user_registry_history, database.user_registry_history);
+        assert_eq!(loaded.user_registry_history.len(), 1);
+        assert_eq!(loaded.log_sequence, 3);
+        assert_eq!(loaded.valid_permissions, database.valid_permissions);
+        assert_eq!(
+            loaded.permission_hierarchy.implications(),
+            database.permission_hierarchy.implications()
+        );
+        assert_eq!(loaded.statement_log.len(), 2);
+        for (statement_id, statement) in &database.statement_log {
+            let loaded_statement = &loaded.statement_log[statement_id];
+            assert_eq!(loaded_statement.sequence, statement.sequence);
+            assert_eq!(loaded_statement.command_raw, statement.command_raw);
+            assert_eq!(loaded_statement.user_set_expr, statement.user_set_expr);
+            assert_eq!(
+                loaded_statement.asset_set_affected,
+                statement.asset_set_affected
+            );
+            assert_eq!(loaded_statement.validity, statement.validity);
+            assert_eq!(loaded_statement.metadata, statement.metadata);
+        }
+        assert_eq!(
+            loaded.export_access(chrono::Utc::now()),
+            database.export_access(chrono::Utc::now())
+        );
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_snapshot_unsupported_version() {
+        let path = temp_path("unsupported_version.json");
+        std::fs::write(&path, "{\"version\": 99}").unwrap();
+
+        match new_database_from_snapshot(&path) {
+            Err(RhubarbError::Snapshot { message, .. }) => {
+                assert_eq!(message, "Unsupported snapshot version 99, expected 1")
+            }
+            other => panic!("Expected a snapshot error, got {:?}", other),
+        }
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_snapshot_missing_file() {
+        let result = new_database_from_snapshot(Path::new("mock_data/does_not_exist.json"));
+
+        assert!(matches!(result, Err(RhubarbError::Snapshot { .. })));
+    }
+}
@@ -0,0 +1,86 @@
+use crate::database::SetRegistry;
+
+const MAX_SUGGESTIONS: usize = 3;
+
+// nearest existing keys to an unknown key
+// keys sharing the attribute prefix (e.g. designation:) are preferred, then the smallest edit distance
+pub fn suggest_keys(key: &str, registry: &SetRegistry) -> Vec<String> {
+    let prefix = attribute_prefix(key);
+    // allow roughly one typo per three characters of the value
+    let value = key.split_once(':').map_or(key, |(_, value)| value);
+    let max_distance = (value.chars().count() / 3).max(2);
+
+    let mut candidates: Vec<(bool, usize, &String)> = registry
+        .keys()
+        .map(|candidate| {
+            (
+                attribute_prefix(candidate) != prefix,
+                edit_distance(key, candidate),
+                candidate,
+            )
+        })
+        .filter(|(_, distance, _)| *distance <= max_distance)
+        .collect();
+
+    candidates.sort();
+
+    candidates
+        .into_iter()
+        .take(MAX_SUGGESTIONS)
+        .map(|(_, _, candidate)| candidate.clone())
+        .collect()
+}
+
+fn attribute_prefix(key: &str) -> Option<&str> {
+    key.split_once(':').map(|(prefix, _)| prefix)
+}
+
+// levenshtein distance over chars
+pub fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+
+    for (i, a_char) in a.chars().enumerate() {
+        let mut current = vec![i + 1; b.len() + 1];
+        for (j, b_char) in b.iter().enumerate() {
+            let substitution = previous[j] + usize::from(a_char != *b_char);
+            current[j + 1] = substitution.min(previous[j + 1] + 1).min(current[j] + 1);
+        }
+        previous = current;
+    }
+
+    previous[b.len()]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_edit_distance() {
+        assert_eq!(edit_distance("senior", "senior"), 0);
+        assert_eq!(edit_distance("seniro", "senior"), 2);
+        assert_eq!(edit_distance("tax", "taxes"), 2);
+        assert_eq!(edit_distance("", "abc"), 3);
+    }
+
+    #[test]
+    fn test_suggest_keys_prefers_same_prefix() {
+        // arrange
+        let mut registry = SetRegistry::new();
+        for key in [
+            "designation:senior",
+            "department:senior",
+            "designation:partner",
+        ] {
+            registry.insert(key.to_string(), "1".to_string());
+        }
+
+        // act
+        let suggestions = suggest_keys("designation:seniro", &registry);
+
+        // assert
+        assert_eq!(suggestions, vec!["designation:senior"]);
+        assert!(suggest_keys("division:unknown", &registry).is_empty());
+    }
+}
@@ -0,0 +1,25 @@
+// fixtures shared by the test modules
+use std::path::{Path, PathBuf};
+
+use chrono::{DateTime, Utc};
+
+use crate::{database::Database, database_pipeline::new_database_from_files};
+
+// the database as loaded from the mock data
+pub fn mock_database() -> Database {
+    new_database_from_files(
+        Path::new("database_config.toml"),
+        Path::new("mock_data/assets.csv"),
+        Path::new("mock_data/employees.csv"),
+    )
+    .unwrap()
+}
+
+// a path in the temp directory, unique to the test process
+pub fn temp_path(file_name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!("rhubarb_{}_{}", std::process::id(), file_name))
+}
+
+pub fn datetime(s: &str) -> DateTime<Utc> {
+    s.parse().unwrap()
+}
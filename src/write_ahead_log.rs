@@ -0,0 +1,547 @@
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{ErrorKind, Write},
+    path::{Path, PathBuf},
+};
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    database::{Database, Permission, SetRegistry},
+    error::{Result, RhubarbError},
+    parse_command::Submission,
+    registry_refresh::{RefreshPlan, RegistryDiff, RegistryKind},
+};
+
+// append-only log of every change applied to the database
+// one entry per line: <seq>\t<crc32 of seq and payload, hex>\t<record as json>
+// entries are never rewritten, a snapshot plus the log replays to the current state
+#[derive(Debug)]
+pub struct WriteAheadLog {
+    path: PathBuf,
+    file: File,
+    last_seq: u64,
+}
+
+#[derive(Debug, Clone)]
+pub struct LogEntry {
+    // 1-based and contiguous
+    pub seq: u64,
+    pub record: LogRecord,
+}
+
+// a change to the database, records are applied in sequence order
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum LogRecord {
+    // applied together, e.g. a policy file
+    Statements(Vec<Permission>),
+    // a committed refresh, with the registry that replaced the current one
+    Refresh {
+        kind: RegistryKind,
+        registry: SetRegistry,
+    },
+    // a single membership delta, with the time the registry was stamped with
+    Membership {
+        kind: RegistryKind,
+        key: String,
+        id: String,
+        insert: bool,
+        etl_datetime: DateTime<Utc>,
+    },
+}
+
+impl WriteAheadLog {
+    // creates the log if it does not exist, every existing entry is verified
+    // an unterminated final line is a write torn by a crash and is dropped
+    pub fn open(path: &Path) -> Result<WriteAheadLog> {
+        let contents = read_log(path)?;
+        let complete_len = contents.rfind('\n').map_or(0, |i| i + 1);
+        let entries = parse_entries(path, &contents[..complete_len])?;
+
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .map_err(|err| log_error(path, err))?;
+        if complete_len < contents.len() {
+            file.set_len(complete_len as u64)
+                .map_err(|err| log_error(path, err))?;
+        }
+
+        Ok(WriteAheadLog {
+            path: path.to_path_buf(),
+            file,
+            last_seq: entries.last().map_or(0, |entry| entry.seq),
+        })
+    }
+
+    pub fn last_seq(&self) -> u64 {
+        self.last_seq
+    }
+
+    // the entry is flushed to disk before this returns
+    pub fn append(&mut self, record: &LogRecord) -> Result<u64> {
+        let seq = self.last_seq + 1;
+        let payload = serde_json::to_string(record).map_err(|err| log_error(&self.path, err))?;
+        let line = format!("{}\t{:08x}\t{}\n", seq, checksum(seq, &payload), payload);
+
+        self.file
+            .write_all(line.as_bytes())
+            .and_then(|_| self.file.sync_data())
+            .map_err(|err| log_error(&self.path, err))?;
+
+        self.last_seq = seq;
+        Ok(seq)
+    }
+
+    pub fn entries(&self) -> Result<Vec<LogEntry>> {
+        let contents = read_log(&self.path)?;
+        let complete_len = contents.rfind('\n').map_or(0, |i| i + 1);
+        parse_entries(&self.path, &contents[..complete_len])
+    }
+
+    // applies every record after the database's log sequence, returning how many
+    // the log must reach the database's log sequence, otherwise records are missing in between
+    pub fn replay(&self, database: &mut Database) -> Result<usize> {
+        let entries = self.entries()?;
+        let applied = database.log_sequence;
+        if applied > self.last_seq {
+            return Err(log_error(
+                &self.path,
+                format!(
+                    "Log ends at sequence {} but the database reflects sequence {}",
+                    self.last_seq, applied
+                ),
+            ));
+        }
+
+        // statements are inserted in bulk, the access matrix is rebuilt before anything else
+        let mut rebuild = false;
+        let mut replayed = 0;
+        for entry in entries.into_iter().filter(|entry| entry.seq > applied) {
+            if entry.seq != database.log_sequence + 1 {
+                return Err(log_error(
+                    &self.path,
+                    format!(
+                        "Sequence {} does not follow sequence {}",
+                        entry.seq, database.log_sequence
+                    ),
+                ));
+            }
+
+            if !matches!(entry.record, LogRecord::Statements(_)) && std::mem::take(&mut rebuild) {
+                database.rebuild_access_matrix();
+            }
+            match entry.record {
+                LogRecord::Statements(statements) => {
+                    for statement in statements {
+                        database
+                            .statement_log
+                            .insert(statement.statement_id.clone(), statement);
+                    }
+                    rebuild = true;
+                }
+                LogRecord::Refresh { kind, registry } => {
+                    let plan = database.plan_refresh(kind, registry);
+                    database.apply_refresh(plan);
+                }
+                LogRecord::Membership {
+                    kind,
+                    key,
+                    id,
+                    insert,
+                    etl_datetime,
+                } => {
+                    database.update_membership(kind, &key, &id, insert, etl_datetime);
+                }
+            }
+            database.log_sequence = entry.seq;
+            replayed += 1;
+        }
+
+        if rebuild {
+            database.rebuild_access_matrix();
+        }
+        Ok(replayed)
+    }
+}
+
+// each change is logged before it is applied
+impl Database {
+    // as apply_command_as
+    pub fn apply_command_logged(
+        &mut self,
+        input: &str,
+        submission: &Submission,
+        log: &mut WriteAheadLog,
+    ) -> Result<String> {
+        let statement = self.prepare_statement(input, submission)?;
+        self.log_sequence = log.append(&LogRecord::Statements(vec![statement.clone()]))?;
+        Ok(self.insert_statement(statement))
+    }
+
+    // as apply_commands, the batch is a single entry so it is replayed all or nothing
+    pub fn apply_commands_logged(
+        &mut self,
+        inputs: &[&str],
+        submission: &Submission,
+        log: &mut WriteAheadLog,
+    ) -> Result<Vec<String>> {
+        let statements = self.prepare_statements(inputs, submission)?;
+        self.log_sequence = log.append(&LogRecord::Statements(statements.clone()))?;
+        Ok(self.insert_statements(statements))
+    }
+
+    // as commit_refresh, a stale plan is rejected before anything is logged
+    pub fn commit_refresh_logged(
+        &mut self,
+        plan: RefreshPlan,
+        log: &mut WriteAheadLog,
+    ) -> Result<RegistryDiff> {
+        self.check_refresh_plan(&plan)?;
+        self.log_sequence = log.append(&LogRecord::Refresh {
+            kind: plan.kind,
+            registry: plan.registry.clone(),
+        })?;
+        Ok(self.apply_refresh(plan))
+    }
+
+    // as insert_membership / delete_membership, a delta that changes nothing is not logged
+    pub fn update_membership_logged(
+        &mut self,
+        kind: RegistryKind,
+        key: &str,
+        id: &str,
+        insert: bool,
+        log: &mut WriteAheadLog,
+    ) -> Result<Vec<String>> {
+        if self.registry(kind).is_member(key, id) == insert {
+            return Ok(Vec::new());
+        }
+
+        let etl_datetime = Utc::now();
+        self.log_sequence = log.append(&LogRecord::Membership {
+            kind,
+            key: key.to_string(),
+            id: id.to_string(),
+            insert,
+            etl_datetime,
+        })?;
+        Ok(self.update_membership(kind, key, id, insert, etl_datetime))
+    }
+}
+
+fn read_log(path: &Path) -> Result<String> {
+    match fs::read_to_string(path) {
+        Ok(contents) => Ok(contents),
+        Err(err) if err.kind() == ErrorKind::NotFound => Ok(String::new()),
+        Err(err) => Err(log_error(path, err)),
+    }
+}
+
+fn parse_entries(path: &Path, contents: &str) -> Result<Vec<LogEntry>> {
+    let mut entries: Vec<LogEntry> = Vec::new();
+
+    for (i, line) in contents.lines().enumerate() {
+        let line_error = |message: &str| log_error(path, format!("Line {}: {}", i + 1, message));
+
+        let mut fields = line.splitn(3, '\t');
+        let (Some(seq), Some(crc), Some(payload)) = (fields.next(), fields.next(), fields.next())
+        else {
+            return Err(line_error("malformed entry"));
+        };
+
+        let seq: u64 = seq.parse().map_err(|_| line_error("malformed sequence"))?;
+        let expected_seq = entries.last().map_or(1, |entry| entry.seq + 1);
+        if seq != expected_seq {
+            return Err(line_error(&format!(
+                "sequence {} out of order, expected {}",
+                seq, expected_seq
+            )));
+        }
+
+        let crc = u32::from_str_radix(crc, 16).map_err(|_| line_error("malformed checksum"))?;
+        if crc != checksum(seq, payload) {
+            return Err(line_error("checksum mismatch"));
+        }
+
+        let record = serde_json::from_str::<LogRecord>(payload)
+            .map_err(|err| line_error(&err.to_string()))?;
+        entries.push(LogEntry { seq, record });
+    }
+
+    Ok(entries)
+}
+
+// covers the sequence as well so an entry cannot be moved without detection
+fn checksum(seq: u64, payload: &str) -> u32 {
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(seq.to_string().as_bytes());
+    hasher.update(b"\t");
+    hasher.update(payload.as_bytes());
+    hasher.finalize()
+}
+
+fn log_error(path: &Path, err: impl ToString) -> RhubarbError {
+    RhubarbError::WriteAheadLog {
+        path: path.to_path_buf(),
+        message: err.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        snapshot::new_database_from_snapshot,
+        test_support::{mock_database, temp_path},
+    };
+
+    #[test]
+    fn test_replay_on_top_of_snapshot() {
+        // arrange
+        let log_path = temp_path("replay.log");
+        let snapshot_path = temp_path("replay_snapshot.json");
+        let submission = Submission::new("carol");
+        let mut database = mock_database();
+        let mut log = WriteAheadLog::open(&log_path).unwrap();
+
+        // act - the snapshot is taken after the first statement only
+        database
+            .apply_command_logged(
+                "GRANT READ ON schema:inventory TO department:tax",
+                &submission,
+                &mut log,
+            )
+            .unwrap();
+        database.save_snapshot(&snapshot_path).unwrap();
+        database
+            .apply_command_logged(
+                "REVOKE READ ON table:products FROM designation:senior",
+                &submission,
+                &mut log,
+            )
+            .unwrap();
+        drop(log);
+
+        let mut restored = new_database_from_snapshot(&snapshot_path).unwrap();
+        let log = WriteAheadLog::open(&log_path).unwrap();
+        let replayed = log.replay(&mut restored).unwrap();
+
+        // assert
+        assert_eq!(log.last_seq(), 2);
+        assert_eq!(replayed, 1);
+        assert_eq!(restored.statement_log.len(), 2);
+        assert_eq!(
+            restored.export_access(chrono::Utc::now()),
+            database.export_access(chrono::Utc::now())
+        );
+        fs::remove_file(log_path).unwrap();
+        fs::remove_file(snapshot_path).unwrap();
+    }
+
+    #[test]
+    fn test_replay_refreshes_and_membership_deltas() {
+        // arrange
+        let log_path = temp_path("replay_refresh.log");
+        let snapshot_path = temp_path("replay_refresh_snapshot.json");
+        let submission = Submission::new("carol");
+        let user_id = "laura.martinez@example.com";
+        let mut database = mock_database();
+        let mut log = WriteAheadLog::open(&log_path).unwrap();
+        database
+            .apply_command_logged(
+                "GRANT READ ON table:items TO department:tax",
+                &submission,
+                &mut log,
+            )
+            .unwrap();
+        database.save_snapshot(&snapshot_path).unwrap();
+
+        // act - a refresh, a delta and a batch of commands after the snapshot
+        let mut registry = database.user_registry.clone();
+        registry.delete("department:tax", user_id);
+        let plan = database.plan_refresh(RegistryKind::User, registry);
+        database.commit_refresh_logged(plan, &mut log).unwrap();
+        database
+            .update_membership_logged(
+                RegistryKind::User,
+                "department:tax",
+                user_id,
+                true,
+                &mut log,
+            )
+            .unwrap();
+        database
+            .apply_commands_logged(
+                &[
+                    "DENY READ ON table:items TO designation:senior",
+                    "GRANT WRITE ON schema:inventory TO department:tax",
+                ],
+                &submission,
+                &mut log,
+            )
+            .unwrap();
+        drop(log);
+
+        let mut restored = new_database_from_snapshot(&snapshot_path).unwrap();
+        let log = WriteAheadLog::open(&log_path).unwrap();
+        let replayed = log.replay(&mut restored).unwrap();
+
+        // assert
+        assert_eq!(replayed, 3);
+        assert_eq!(restored.log_sequence, 4);
+        assert_eq!(restored.statement_log.len(), 3);
+        assert!(restored.user_registry.is_member("department:tax", user_id));
+        assert_eq!(restored.user_registry, database.user_registry);
+        assert_eq!(
+            restored.user_registry_history,
+            database.user_registry_history
+        );
+        assert_eq!(
+            restored.export_access(chrono::Utc::now()),
+            database.export_access(chrono::Utc::now())
+        );
+        fs::remove_file(log_path).unwrap();
+        fs::remove_file(snapshot_path).unwrap();
+    }
+
+    #[test]
+    fn test_replay_detects_missing_records() {
+        // arrange
+        let path = temp_path("missing.log");
+        let mut database = mock_database();
+        let mut log = WriteAheadLog::open(&path).unwrap();
+        database
+            .apply_command_logged(
+                "GRANT READ ON schema:inventory TO department:tax",
+                &Submission::new("carol"),
+                &mut log,
+            )
+            .unwrap();
+        drop(log);
+
+        // act - the database reflects more of the log than survives, e.g. an older log restored
+        let mut restored = mock_database();
+        restored.log_sequence = 3;
+        let result = WriteAheadLog::open(&path).unwrap().replay(&mut restored);
+
+        // assert
+        match result {
+            Err(RhubarbError::WriteAheadLog { message, .. }) => assert_eq!(
+                message,
+                "Log ends at sequence 1 but the database reflects sequence 3"
+            ),
+            other => panic!("Expected a write-ahead log error, got {:?}", other),
+        }
+        assert!(restored.statement_log.is_empty());
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_entry_that_is_not_a_record_is_rejected() {
+        // arrange
+        let path = temp_path("not_a_record.log");
+        let database = mock_database();
+        let statement = database
+            .prepare_statement(
+                "GRANT READ ON table:items TO department:tax",
+                &Submission::new("carol"),
+            )
+            .unwrap();
+        let payload = serde_json::to_string(&statement).unwrap();
+        fs::write(
+            &path,
+            format!("1\t{:08x}\t{}\n", checksum(1, &payload), payload),
+        )
+        .unwrap();
+
+        // act
+        let result = WriteAheadLog::open(&path);
+
+        // assert
+        assert!(matches!(result, Err(RhubarbError::WriteAheadLog { .. })));
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_checksum_mismatch() {
+        // arrange
+        let path = temp_path("checksum.log");
+        let mut database = mock_database();
+        let mut log = WriteAheadLog::open(&path).unwrap();
+        database
+            .apply_command_logged(
+                "GRANT READ ON schema:inventory TO department:tax",
+                &Submission::new("carol"),
+                &mut log,
+            )
+            .unwrap();
+        drop(log);
+
+        // act - tamper with the logged command
+        let contents = fs::read_to_string(&path).unwrap();
+        fs::write(&path, contents.replace("department:tax", "department:hr_")).unwrap();
+
+        // assert
+        match WriteAheadLog::open(&path) {
+            Err(RhubarbError::WriteAheadLog { message, .. }) => {
+                assert_eq!(message, "Line 1: checksum mismatch")
+            }
+            other => panic!("Expected a write-ahead log error, got {:?}", other),
+        }
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_torn_final_entry_is_dropped() {
+        let path = temp_path("torn.log");
+        let mut database = mock_database();
+        let mut log = WriteAheadLog::open(&path).unwrap();
+        database
+            .apply_command_logged(
+                "GRANT READ ON schema:inventory TO department:tax",
+                &Submission::new("carol"),
+                &mut log,
+            )
+            .unwrap();
+        drop(log);
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(b"2\t0000").unwrap();
+
+        let mut log = WriteAheadLog::open(&path).unwrap();
+        assert_eq!(log.last_seq(), 1);
+        assert_eq!(log.entries().unwrap().len(), 1);
+
+        // appending after recovery continues the sequence
+        database
+            .apply_command_logged(
+                "DENY READ ON table:products TO designation:senior",
+                &Submission::new("carol"),
+                &mut log,
+            )
+            .unwrap();
+        assert_eq!(log.entries().unwrap().len(), 2);
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_out_of_order_entry() {
+        let path = temp_path("out_of_order.log");
+        let payload = "{}";
+        fs::write(
+            &path,
+            format!("2\t{:08x}\t{}\n", checksum(2, payload), payload),
+        )
+        .unwrap();
+
+        match WriteAheadLog::open(&path) {
+            Err(RhubarbError::WriteAheadLog { message, .. }) => {
+                assert_eq!(message, "Line 1: sequence 2 out of order, expected 1")
+            }
+            other => panic!("Expected a write-ahead log error, got {:?}", other),
+        }
+        fs::remove_file(path).unwrap();
+    }
+}